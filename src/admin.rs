@@ -0,0 +1,221 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::sync::atomic::{AtomicU64, Ordering};
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::protocol::{ErrorCode, KodaSignal, Severity};
+use crate::AppState;
+
+/// Force every device of `user_id` off this node. Requires `Authorization: Bearer $ADMIN_TOKEN`.
+pub async fn kick(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if let Err(status) = authorize(&state, &headers) {
+        return (status, Json(json!({ "error": "unauthorized" })));
+    }
+
+    // Clone out of the map first; the sockets unregister themselves as they tear down
+    let Some(connections) = state.peers.get(&user_id).map(|entry| entry.clone()) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "not_connected" })));
+    };
+    for peer in &connections {
+        peer.kick(ErrorCode::Kicked);
+    }
+    warn!(%user_id, devices = connections.len(), "User kicked by admin");
+    (StatusCode::OK, Json(json!({ "user_id": user_id, "devices": connections.len() })))
+}
+
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct PeersPage {
+    // Resume after this user_id, as returned in `next`
+    after: Option<Uuid>,
+    limit: Option<usize>,
+}
+
+/// Users connected to this node, ordered by id so `after` pages stay stable while the map changes.
+pub async fn peers(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(page): Query<PeersPage>,
+) -> impl IntoResponse {
+    if let Err(status) = authorize(&state, &headers) {
+        return (status, Json(json!({ "error": "unauthorized" })));
+    }
+
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let mut user_ids: Vec<Uuid> = state
+        .peers
+        .iter()
+        .map(|entry| *entry.key())
+        .filter(|user_id| page.after.is_none_or(|after| *user_id > after))
+        .collect();
+    user_ids.sort_unstable();
+    let next = (user_ids.len() > limit).then(|| user_ids[limit - 1]);
+    user_ids.truncate(limit);
+
+    // A user may disconnect between listing and lookup; they are just left out
+    let peers: Vec<_> = user_ids
+        .into_iter()
+        .filter_map(|user_id| {
+            let connections = state.peers.get(&user_id)?;
+            let connected_at: Vec<i64> = connections.iter().map(|peer| peer.info.connected_at).collect();
+            // Totals across the user's devices; per-device figures are on /admin/connections
+            let total = |count: fn(&crate::Traffic) -> &AtomicU64| -> u64 {
+                connections.iter().map(|peer| count(&peer.info.traffic).load(Ordering::Relaxed)).sum()
+            };
+            Some(json!({
+                "user_id": user_id,
+                "devices": connected_at.len(),
+                "connected_at": connected_at,
+                "messages_in": total(|traffic| &traffic.messages_in),
+                "bytes_in": total(|traffic| &traffic.bytes_in),
+                "messages_out": total(|traffic| &traffic.messages_out),
+                "bytes_out": total(|traffic| &traffic.bytes_out),
+            }))
+        })
+        .collect();
+    (StatusCode::OK, Json(json!({ "total": state.peers.len(), "peers": peers, "next": next })))
+}
+
+/// Where and when each device of `user_id` connected from, for support and abuse investigations.
+pub async fn connections(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if let Err(status) = authorize(&state, &headers) {
+        return (status, Json(json!({ "error": "unauthorized" })));
+    }
+
+    let Some(connections) = state.peers.get(&user_id).map(|entry| {
+        entry
+            .iter()
+            .filter_map(|peer| {
+                let mut connection = serde_json::to_value(&*peer.info).ok()?;
+                connection["connection_id"] = json!(peer.connection_id);
+                Some(connection)
+            })
+            .collect::<Vec<_>>()
+    }) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "not_connected" })));
+    };
+    (StatusCode::OK, Json(json!({ "user_id": user_id, "connections": connections })))
+}
+
+/// Every user on this node as `[user_id, devices, last_activity]`, sorted by id: compact enough to
+/// poll while draining, so an orchestrator can pre-warm the nodes those users will reconnect to.
+pub async fn registry(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Err(status) = authorize(&state, &headers) {
+        return (status, Json(json!({ "error": "unauthorized" })));
+    }
+
+    let mut users: Vec<(Uuid, usize, i64)> = state
+        .peers
+        .iter()
+        .map(|entry| {
+            // A device that hasn't sent anything yet was last active when it connected
+            let last_activity = entry
+                .iter()
+                .map(|peer| peer.info.traffic.last_activity.load(Ordering::Relaxed).max(peer.info.connected_at))
+                .max()
+                .unwrap_or_default();
+            (*entry.key(), entry.len(), last_activity)
+        })
+        .collect();
+    users.sort_unstable_by_key(|(user_id, ..)| *user_id);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "node_id": state.config.node_id,
+            "draining": state.draining.load(Ordering::Relaxed),
+            "generated_at": crate::unix_millis(),
+            "users": users,
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct Announcement {
+    message: String,
+    #[serde(default)]
+    severity: Severity,
+}
+
+/// Best-effort ANNOUNCEMENT to every device connected to this node.
+pub async fn broadcast(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(announcement): Json<Announcement>,
+) -> impl IntoResponse {
+    if let Err(status) = authorize(&state, &headers) {
+        return (status, Json(json!({ "error": "unauthorized" })));
+    }
+
+    let signal = KodaSignal::Announcement { message: announcement.message, severity: announcement.severity };
+    let Some(text) = crate::to_json(&signal) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "unserializable" })));
+    };
+    let mut recipients = 0;
+    for entry in state.peers.iter() {
+        for peer in entry.value() {
+            if peer.send_text(&text).is_ok() {
+                recipients += 1;
+            }
+        }
+    }
+    info!(recipients, severity = ?announcement.severity, "Announcement broadcast by admin");
+    (StatusCode::OK, Json(json!({ "recipients": recipients })))
+}
+
+/// Stops accepting new sockets and reports not-ready, while existing sessions carry on.
+pub async fn drain(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    set_draining(&state, &headers, true)
+}
+
+pub async fn undrain(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    set_draining(&state, &headers, false)
+}
+
+fn set_draining(state: &AppState, headers: &HeaderMap, draining: bool) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(status) = authorize(state, headers) {
+        return (status, Json(json!({ "error": "unauthorized" })));
+    }
+    state.draining.store(draining, Ordering::Relaxed);
+    warn!(draining, "Drain mode changed by admin");
+    (StatusCode::OK, Json(json!({ "draining": draining })))
+}
+
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    // Without ADMIN_TOKEN the admin API is switched off entirely
+    let Some(expected) = &state.config.admin_token else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+// Don't leak how much of the token matched, or how long the secret is, through response timing.
+// Comparing fixed-length digests means the time taken never depends on either length.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    Sha1::digest(a).as_slice().ct_eq(Sha1::digest(b).as_slice()).into()
+}
@@ -0,0 +1,324 @@
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind as JwtErrorKind};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{dangerous, decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+// Use the local Claims struct which matches koda-api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
+}
+
+// What a token actually carries; the subject may sit under a different claim than `sub`
+#[derive(Deserialize)]
+struct TokenClaims {
+    exp: usize,
+    #[serde(default)]
+    nbf: Option<usize>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Claims {
+    /// Time left until `exp` plus `leeway`, zero if that has already passed.
+    pub fn expires_in(&self, leeway: Duration) -> Duration {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        (Duration::from_secs(self.exp as u64) + leeway).saturating_sub(now)
+    }
+
+    /// Time until `nbf` minus `leeway`, zero for a token that is already usable.
+    pub fn usable_in(&self, leeway: Duration) -> Duration {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let Some(nbf) = self.nbf else { return Duration::ZERO };
+        Duration::from_secs(nbf as u64).saturating_sub(leeway).saturating_sub(now)
+    }
+}
+
+// Built once at startup so the hot Identify path never touches env or disk
+#[derive(Clone)]
+pub struct JwtVerifier {
+    keys: Keys,
+    // Looked up before `sub`
+    subject_claim: String,
+}
+
+#[derive(Clone)]
+enum Keys {
+    Static(Arc<(DecodingKey, Validation)>),
+    // Selected per token by its `kid` header
+    Jwks(Arc<Jwks>),
+}
+
+struct Jwks {
+    url: String,
+    client: reqwest::Client,
+    // Used for keys whose JWK doesn't name an algorithm
+    default_algorithm: Algorithm,
+    // Claim checks shared by every key; only the algorithm differs per key
+    validation: Validation,
+    keys: RwLock<HashMap<String, (DecodingKey, Validation)>>,
+}
+
+fn validation(algorithm: Algorithm, config: &Config) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    // Tolerates clock skew between koda-api, the client and this node on `exp`
+    validation.leeway = config.jwt_leeway.as_secs();
+    // Tokens minted for later use must not open a session early
+    validation.validate_nbf = true;
+    // Tokens minted for another koda service must not open a session here
+    if !config.jwt_audience.is_empty() {
+        validation.set_audience(&config.jwt_audience);
+        validation.required_spec_claims.insert("aud".to_owned());
+    }
+    if !config.jwt_issuer.is_empty() {
+        validation.set_issuer(&config.jwt_issuer);
+        validation.required_spec_claims.insert("iss".to_owned());
+    }
+    validation
+}
+
+impl JwtVerifier {
+    /// HMAC algorithms use the shared secret; RSA/EC/EdDSA algorithms use the PEM public key.
+    /// With `JWKS_URL` set, keys come from koda-api instead and must be loaded with `refresh`.
+    pub fn new(config: &Config) -> Self {
+        let algorithm = config.jwt_algorithm;
+        let subject_claim = config.jwt_subject_claim.clone();
+        if let Some(url) = &config.jwks_url {
+            return JwtVerifier {
+                subject_claim,
+                keys: Keys::Jwks(Arc::new(Jwks {
+                    url: url.clone(),
+                    client: reqwest::Client::builder()
+                        .timeout(Duration::from_secs(5))
+                        .build()
+                        .expect("failed to build HTTP client"),
+                    default_algorithm: algorithm,
+                    validation: validation(algorithm, config),
+                    keys: RwLock::new(HashMap::new()),
+                })),
+            };
+        }
+
+        let key = match (&config.jwt_secret, &config.jwt_public_key_pem) {
+            (Some(secret), _) if matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) => {
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            (_, Some(pem)) => {
+                let parsed = match algorithm {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+                    _ => DecodingKey::from_rsa_pem(pem),
+                };
+                parsed.unwrap_or_else(|e| panic!("Invalid public key in JWT_PUBLIC_KEY_PATH: {}", e))
+            }
+            // Config::from_env guarantees the matching key material is present
+            _ => unreachable!("no JWT key material for {:?}", algorithm),
+        };
+
+        JwtVerifier { keys: Keys::Static(Arc::new((key, validation(algorithm, config)))), subject_claim }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, JwtError> {
+        match &self.keys {
+            Keys::Static(static_key) => {
+                let (key, validation) = &**static_key;
+                self.claims(decode::<TokenClaims>(token, key, validation)?.claims)
+            }
+            Keys::Jwks(jwks) => {
+                let kid = decode_header(token)?.kid.ok_or(JwtErrorKind::InvalidToken)?;
+                let keys = jwks.keys.read().unwrap();
+                // Unknown ids are rejected until a refresh picks the key up
+                let (key, validation) = keys.get(&kid).ok_or(JwtErrorKind::InvalidToken)?;
+                self.claims(decode::<TokenClaims>(token, key, validation)?.claims)
+            }
+        }
+    }
+
+    // Some issuers send the user id under another name; either way it must be a UUID string
+    fn claims(&self, token: TokenClaims) -> Result<Claims, JwtError> {
+        let subject = token
+            .other
+            .get(&self.subject_claim)
+            .or_else(|| token.other.get("sub"))
+            .and_then(|value| value.as_str())
+            .and_then(|value| Uuid::parse_str(value).ok())
+            .ok_or(JwtErrorKind::InvalidToken)?;
+        Ok(Claims { sub: subject, exp: token.exp, nbf: token.nbf })
+    }
+
+    /// How long until a token `verify` refused with `ImmatureSignature` becomes usable.
+    /// That error is only raised once the signature has checked out, so its claims can be read.
+    pub fn usable_in(&self, token: &str, leeway: Duration) -> Option<Duration> {
+        let claims = self.claims(dangerous::insecure_decode::<TokenClaims>(token).ok()?.claims).ok()?;
+        Some(claims.usable_in(leeway))
+    }
+
+    /// Fetches the JWKS document and swaps in its keys; a no-op in static-key mode.
+    /// On failure the last good set stays in use.
+    pub async fn refresh(&self) -> Result<(), String> {
+        let Keys::Jwks(jwks) = &self.keys else { return Ok(()) };
+        let set: JwkSet = jwks
+            .client
+            .get(&jwks.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut keys = HashMap::new();
+        for jwk in &set.keys {
+            let Some(kid) = &jwk.common.key_id else { continue };
+            let algorithm = match &jwk.common.key_algorithm {
+                Some(alg) => match alg.to_string().parse() {
+                    Ok(algorithm) => algorithm,
+                    // e.g. encryption keys published alongside signing keys
+                    Err(_) => continue,
+                },
+                None => jwks.default_algorithm,
+            };
+            match DecodingKey::from_jwk(jwk) {
+                Ok(key) => {
+                    let mut validation = jwks.validation.clone();
+                    validation.algorithms = vec![algorithm];
+                    keys.insert(kid.clone(), (key, validation));
+                }
+                Err(e) => warn!(%kid, error = %e, "Skipping unusable JWK"),
+            }
+        }
+        if keys.is_empty() {
+            return Err("JWKS contains no usable signing keys".to_owned());
+        }
+        info!(keys = keys.len(), "Loaded JWKS");
+        *jwks.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    pub fn spawn_refresh(&self, every: Duration) {
+        if !matches!(self.keys, Keys::Jwks(_)) {
+            return;
+        }
+        let verifier = self.clone();
+        tokio::spawn(async move {
+            let mut tick = time::interval(every);
+            // The first tick fires immediately, and startup has just loaded the keys
+            tick.tick().await;
+            loop {
+                tick.tick().await;
+                if let Err(e) = verifier.refresh().await {
+                    warn!(error = %e, "JWKS refresh failed, keeping previous keys");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_tokens::{now, TokenMint};
+
+    const SECRET: &str = "unit-test-secret-of-at-least-32-bytes";
+
+    fn verifier_with(settings: &[(&str, &str)]) -> JwtVerifier {
+        let config = Config::from_lookup(|key| match key {
+            "JWT_SECRET" => Some(SECRET.to_owned()),
+            _ => settings.iter().find(|(name, _)| *name == key).map(|(_, value)| value.to_string()),
+        })
+        .unwrap();
+        JwtVerifier::new(&config)
+    }
+
+    fn verifier(leeway_secs: &str) -> JwtVerifier {
+        verifier_with(&[("JWT_LEEWAY_SECS", leeway_secs)])
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        TokenMint::new(SECRET).sign(claims)
+    }
+
+    fn token_expiring_in(secs: i64) -> String {
+        TokenMint::new(SECRET).expiring_in(Uuid::new_v4(), secs)
+    }
+
+    #[test]
+    fn token_expired_within_leeway_is_accepted() {
+        assert!(verifier("30").verify(&token_expiring_in(-10)).is_ok());
+    }
+
+    #[test]
+    fn token_expired_beyond_leeway_is_rejected() {
+        let error = verifier("30").verify(&token_expiring_in(-60)).unwrap_err();
+        assert_eq!(error.kind(), &JwtErrorKind::ExpiredSignature);
+    }
+
+    #[test]
+    fn token_used_before_nbf_is_rejected() {
+        let mint = TokenMint::new(SECRET);
+        let error = verifier("30").verify(&mint.not_yet_valid(Uuid::new_v4(), 600)).unwrap_err();
+        assert_eq!(error.kind(), &JwtErrorKind::ImmatureSignature);
+        assert!(verifier("30").verify(&mint.not_yet_valid(Uuid::new_v4(), 10)).is_ok(), "nbf gets the same leeway as exp");
+    }
+
+    #[test]
+    fn wait_for_a_future_token_allows_for_the_leeway() {
+        let verifier = verifier("30");
+        let mint = TokenMint::new(SECRET);
+        let wait = verifier.usable_in(&mint.not_yet_valid(Uuid::new_v4(), 600), Duration::from_secs(30)).unwrap();
+        assert!((569..=570).contains(&wait.as_secs()), "expected about 570s, got {:?}", wait);
+        let within_leeway = verifier.usable_in(&mint.not_yet_valid(Uuid::new_v4(), 10), Duration::from_secs(30));
+        assert_eq!(within_leeway, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn audience_and_issuer_must_match_when_configured() {
+        let verifier = verifier_with(&[("JWT_AUDIENCE", "koda-signal"), ("JWT_ISSUER", "koda-api")]);
+        let token = |aud: &str, iss: &str| {
+            sign(serde_json::json!({ "sub": Uuid::new_v4(), "exp": now() + 60, "aud": aud, "iss": iss }))
+        };
+
+        assert!(verifier.verify(&token("koda-signal", "koda-api")).is_ok());
+        let wrong_audience = verifier.verify(&token("koda-billing", "koda-api")).unwrap_err();
+        assert_eq!(wrong_audience.kind(), &JwtErrorKind::InvalidAudience);
+        let wrong_issuer = verifier.verify(&token("koda-signal", "someone-else")).unwrap_err();
+        assert_eq!(wrong_issuer.kind(), &JwtErrorKind::InvalidIssuer);
+        assert!(verifier.verify(&token_expiring_in(60)).is_err(), "tokens without aud/iss must be rejected");
+    }
+
+    #[test]
+    fn subject_can_come_from_a_configured_claim() {
+        let verifier = verifier_with(&[("JWT_SUBJECT_CLAIM", "uid")]);
+        let user_id = Uuid::new_v4();
+        let claims = verifier.verify(&sign(serde_json::json!({ "uid": user_id, "exp": now() + 60 }))).unwrap();
+        assert_eq!(claims.sub, user_id);
+        // `sub` still works for tokens from issuers that use it
+        let claims = verifier.verify(&sign(serde_json::json!({ "sub": user_id, "exp": now() + 60 }))).unwrap();
+        assert_eq!(claims.sub, user_id);
+    }
+
+    #[test]
+    fn missing_or_unparseable_subject_is_an_invalid_token() {
+        let verifier = verifier("30");
+        for claims in [
+            serde_json::json!({ "exp": now() + 60 }),
+            serde_json::json!({ "sub": "not-a-uuid", "exp": now() + 60 }),
+            serde_json::json!({ "sub": 42, "exp": now() + 60 }),
+        ] {
+            let error = verifier.verify(&sign(claims)).unwrap_err();
+            assert_eq!(error.kind(), &JwtErrorKind::InvalidToken);
+        }
+    }
+}
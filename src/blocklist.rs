@@ -0,0 +1,33 @@
+use dashmap::DashMap;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+// In-memory only: a user's blocks last until their last device disconnects
+#[derive(Default)]
+pub struct Blocklist {
+    // user -> peers whose signals they refuse
+    blocked: DashMap<Uuid, HashSet<Uuid>>,
+}
+
+impl Blocklist {
+    pub fn block(&self, user_id: Uuid, peer_id: Uuid) {
+        if peer_id != user_id {
+            self.blocked.entry(user_id).or_default().insert(peer_id);
+        }
+    }
+
+    pub fn unblock(&self, user_id: Uuid, peer_id: Uuid) {
+        if let Some(mut set) = self.blocked.get_mut(&user_id) {
+            set.remove(&peer_id);
+        }
+        self.blocked.remove_if(&user_id, |_, set| set.is_empty());
+    }
+
+    pub fn is_blocked(&self, target_id: Uuid, sender_id: Uuid) -> bool {
+        self.blocked.get(&target_id).is_some_and(|set| set.contains(&sender_id))
+    }
+
+    pub fn clear(&self, user_id: Uuid) {
+        self.blocked.remove(&user_id);
+    }
+}
@@ -0,0 +1,176 @@
+use futures::StreamExt;
+use redis::aio::{ConnectionManager, PubSubSink};
+use redis::{AsyncCommands, Client, RedisResult, Script};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+
+// Only delete the presence key if this node still owns it; another node may have claimed it since
+const RELEASE_PRESENCE: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Cross-node routing over Redis pub/sub.
+///
+/// Every node subscribes to `koda:peer:{user_id}` for each locally connected user and records
+/// `koda:presence:{user_id}` with a TTL, so other nodes only publish to users known to be online.
+pub struct Cluster {
+    node_id: String,
+    client: Client,
+    commands: ConnectionManager,
+    // Replaced whenever the subscriber loop reconnects
+    subscriptions: Mutex<Option<PubSubSink>>,
+    presence_ttl: Duration,
+}
+
+fn presence_key(user_id: Uuid) -> String {
+    format!("koda:presence:{}", user_id)
+}
+
+fn peer_channel(user_id: Uuid) -> String {
+    format!("koda:peer:{}", user_id)
+}
+
+impl Cluster {
+    pub async fn connect(url: &str, node_id: String, presence_ttl: Duration) -> RedisResult<Self> {
+        let client = Client::open(url)?;
+        let commands = ConnectionManager::new(client.clone()).await?;
+        Ok(Cluster { node_id, client, commands, subscriptions: Mutex::new(None), presence_ttl })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// A handle on the shared command connection, for other state kept in Redis.
+    pub fn commands(&self) -> ConnectionManager {
+        self.commands.clone()
+    }
+
+    /// Called when a user's first local device identifies.
+    pub async fn claim(&self, user_id: Uuid) {
+        let mut commands = self.commands.clone();
+        let claimed: RedisResult<()> = commands
+            .set_ex(presence_key(user_id), &self.node_id, self.presence_ttl.as_secs())
+            .await;
+        if let Err(e) = claimed {
+            warn!(%user_id, error = %e, "Failed to record presence in Redis");
+        }
+        let sink = self.subscriptions.lock().unwrap().clone();
+        if let Some(mut sink) = sink
+            && let Err(e) = sink.subscribe(peer_channel(user_id)).await
+        {
+            warn!(%user_id, error = %e, "Failed to subscribe to peer channel");
+        }
+    }
+
+    /// Called when a user's last local device disconnects.
+    pub async fn release(&self, user_id: Uuid) {
+        let sink = self.subscriptions.lock().unwrap().clone();
+        if let Some(mut sink) = sink {
+            let _ = sink.unsubscribe(peer_channel(user_id)).await;
+        }
+        let mut commands = self.commands.clone();
+        let released: RedisResult<i32> = Script::new(RELEASE_PRESENCE)
+            .key(presence_key(user_id))
+            .arg(&self.node_id)
+            .invoke_async(&mut commands)
+            .await;
+        if let Err(e) = released {
+            warn!(%user_id, error = %e, "Failed to clear presence in Redis");
+        }
+    }
+
+    /// Publishes an already sender-stamped frame to whichever node holds `target_id`.
+    /// Returns false if the target isn't online anywhere, so the caller can fall back.
+    pub async fn relay(&self, target_id: Uuid, text: &str) -> bool {
+        let mut commands = self.commands.clone();
+        match commands.exists::<_, bool>(presence_key(target_id)).await {
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(e) => {
+                warn!(%target_id, error = %e, "Presence lookup failed");
+                return false;
+            }
+        }
+        match commands.publish::<_, _, usize>(peer_channel(target_id), text).await {
+            Ok(receivers) => receivers > 0,
+            Err(e) => {
+                warn!(%target_id, error = %e, "Failed to relay signal via Redis");
+                false
+            }
+        }
+    }
+
+    // Presence keys expire unless refreshed, so a crashed node's users drop out on their own
+    async fn refresh_presence(&self, state: &AppState) {
+        let users: Vec<Uuid> = state.peers.iter().map(|entry| *entry.key()).collect();
+        if users.is_empty() {
+            return;
+        }
+        let mut pipe = redis::pipe();
+        for user_id in users {
+            pipe.set_ex(presence_key(user_id), &self.node_id, self.presence_ttl.as_secs()).ignore();
+        }
+        let mut commands = self.commands.clone();
+        if let Err(e) = pipe.query_async::<()>(&mut commands).await {
+            warn!(error = %e, "Failed to refresh presence in Redis");
+        }
+    }
+
+    pub fn spawn(self: Arc<Self>, state: AppState) {
+        let refresher = self.clone();
+        let refresh_state = state.clone();
+        tokio::spawn(async move {
+            let mut tick = time::interval(refresher.presence_ttl / 3);
+            loop {
+                tick.tick().await;
+                refresher.refresh_presence(&refresh_state).await;
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.subscribe_and_deliver(&state).await {
+                    warn!(error = %e, "Redis subscriber lost, reconnecting");
+                }
+                *self.subscriptions.lock().unwrap() = None;
+                time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+    }
+
+    async fn subscribe_and_deliver(&self, state: &AppState) -> RedisResult<()> {
+        let (mut sink, mut stream) = self.client.get_async_pubsub().await?.split();
+        *self.subscriptions.lock().unwrap() = Some(sink.clone());
+
+        // Re-subscribe for everyone already connected, e.g. after a Redis restart
+        let users: Vec<String> = state.peers.iter().map(|entry| peer_channel(*entry.key())).collect();
+        if !users.is_empty() {
+            sink.subscribe(&users).await?;
+        }
+        info!(node_id = %self.node_id, users = users.len(), "Subscribed to cross-node routing");
+
+        while let Some(msg) = stream.next().await {
+            let Some(target_id) = msg
+                .get_channel_name()
+                .strip_prefix("koda:peer:")
+                .and_then(|id| Uuid::parse_str(id).ok())
+            else {
+                continue;
+            };
+            let Ok(text) = msg.get_payload::<String>() else { continue };
+            if crate::deliver_local(&state.peers, target_id, &text).is_none() {
+                debug!(%target_id, "Relayed signal arrived after peer left this node");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,461 @@
+use axum::http::{HeaderValue, Method};
+use jsonwebtoken::Algorithm;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3000);
+
+// Every setting the node reads, parsed and validated once at startup
+pub struct Config {
+    pub bind_addr: SocketAddr,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub jwt_algorithm: Algorithm,
+    pub jwt_secret: Option<String>,
+    pub jwt_public_key_pem: Option<Vec<u8>>,
+    pub jwks_url: Option<String>,
+    pub jwks_refresh: Duration,
+    pub jwt_leeway: Duration,
+    pub jwt_subject_claim: String,
+    pub jwt_audience: Vec<String>,
+    pub jwt_issuer: Vec<String>,
+    pub allowed_origins: Vec<String>,
+    // Empty means no CORS headers on the HTTP endpoints; `/pulse` checks origins itself
+    pub cors_origins: Vec<String>,
+    pub cors_methods: Vec<Method>,
+    pub ping_interval: Duration,
+    // Each ping lands up to this far either side of `ping_interval`
+    pub ping_jitter: Duration,
+    pub pong_timeout: Duration,
+    pub send_timeout: Duration,
+    // How long a closing socket's queued frames get to reach the client before the writer is aborted
+    pub send_flush_grace: Duration,
+    pub identify_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub max_connection_lifetime: Duration,
+    pub resume_ttl: Duration,
+    pub rate_limit_per_sec: f64,
+    pub rate_limit_burst: f64,
+    pub channel_capacity: usize,
+    pub slow_consumer_high_water: usize,
+    pub slow_consumer_disconnect: bool,
+    pub max_connections: usize,
+    pub connect_rate_limit: usize,
+    pub connect_rate_window: Duration,
+    pub trust_forwarded_for: bool,
+    pub require_secure: bool,
+    pub max_connections_per_user: usize,
+    pub max_auth_attempts: u32,
+    pub max_payload_bytes: usize,
+    pub signal_dedup_window: Duration,
+    pub signal_data_keys: Vec<String>,
+    pub max_message_bytes: usize,
+    pub max_frame_bytes: usize,
+    pub max_room_members: usize,
+    pub max_rooms_per_user: usize,
+    pub max_subscriptions_per_user: usize,
+    pub shutdown_grace: Duration,
+    pub reconnect_spread: Duration,
+    pub shutdown_redirect_url: Option<String>,
+    pub offline_queue_depth: usize,
+    pub offline_queue_ttl: Duration,
+    pub daily_signal_quota: u64,
+    pub friendship_check: bool,
+    pub dead_letter_path: Option<String>,
+    pub dead_letter_url: Option<String>,
+    pub dead_letter_include_data: bool,
+    pub event_webhook_url: Option<String>,
+    pub relay_nodes: Vec<String>,
+    pub relay_secret: Option<String>,
+    pub koda_api_url: Option<String>,
+    pub koda_api_token: Option<String>,
+    pub friendship_cache_ttl: Duration,
+    pub handle_cache_ttl: Duration,
+    pub reveal_blocks: bool,
+    pub redis_url: Option<String>,
+    pub node_id: String,
+    pub presence_ttl: Duration,
+    pub last_seen_horizon: Duration,
+    pub presence_grace: Duration,
+    pub admin_token: Option<String>,
+    pub stun_urls: Vec<String>,
+    pub turn_urls: Vec<String>,
+    pub turn_secret: Option<String>,
+    pub turn_credential_ttl: Duration,
+}
+
+// Collects every problem so a deploy shows them all at once instead of one per restart
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    /// Same rules as `from_env`, reading settings from `lookup` instead, e.g. a test's fixed map.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut env = EnvReader { lookup: &lookup, problems: Vec::new() };
+
+        let jwt_algorithm = env.parse("JWT_ALG", Algorithm::HS256);
+        let symmetric = matches!(jwt_algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512);
+        let jwt_secret = env.optional("JWT_SECRET");
+        let jwt_public_key_path = env.optional("JWT_PUBLIC_KEY_PATH");
+        let mut jwt_public_key_pem = None;
+        let jwks_url = env.optional("JWKS_URL");
+        let jwks_refresh = env.secs("JWKS_REFRESH_SECS", 300);
+        if jwks_url.is_some() {
+            // Keys come from koda-api; JWT_ALG only applies to JWKs that don't name their algorithm
+            if jwks_refresh.is_zero() {
+                env.problem("JWKS_REFRESH_SECS must be greater than zero");
+            }
+        } else if symmetric {
+            // An empty or short HMAC key makes tokens forgeable, so refuse to start rather than accept them
+            let min_len = match jwt_algorithm {
+                Algorithm::HS384 => 48,
+                Algorithm::HS512 => 64,
+                _ => 32,
+            };
+            match &jwt_secret {
+                None => env.problem("JWT_SECRET must be set and non-empty"),
+                Some(secret) if secret.len() < min_len => env.problem(format!(
+                    "JWT_SECRET must be at least {} bytes for {:?}, got {}",
+                    min_len,
+                    jwt_algorithm,
+                    secret.len()
+                )),
+                Some(_) => {}
+            }
+        } else {
+            match &jwt_public_key_path {
+                None => env.problem("JWT_PUBLIC_KEY_PATH must be set for asymmetric JWT_ALG"),
+                Some(path) => match std::fs::read(path) {
+                    Ok(pem) => jwt_public_key_pem = Some(pem),
+                    Err(e) => env.problem(format!("JWT_PUBLIC_KEY_PATH {} cannot be read: {}", path, e)),
+                },
+            }
+        }
+
+        let ping_interval = env.secs("PING_INTERVAL_SECS", 30);
+        if ping_interval.is_zero() {
+            env.problem("PING_INTERVAL_SECS must be greater than zero");
+        }
+        let ping_jitter_percent: u32 = env.parse("PING_JITTER_PERCENT", 0);
+        if ping_jitter_percent > 50 {
+            env.problem("PING_JITTER_PERCENT must be between 0 and 50");
+        }
+        let ping_jitter = ping_interval * ping_jitter_percent.min(50) / 100;
+        // Two missed pings by default before a socket is considered dead
+        let pong_timeout = env.secs("PONG_TIMEOUT_SECS", ping_interval.as_secs() * 2);
+        let send_timeout = env.secs("SEND_TIMEOUT_SECS", 10);
+        if send_timeout.is_zero() {
+            env.problem("SEND_TIMEOUT_SECS must be greater than zero");
+        }
+
+        let rate_limit_per_sec = env.parse("RATE_LIMIT_PER_SEC", 50.0);
+        if rate_limit_per_sec <= 0.0 {
+            env.problem("RATE_LIMIT_PER_SEC must be greater than zero");
+        }
+        let rate_limit_burst = env.parse("RATE_LIMIT_BURST", 100.0);
+        if rate_limit_burst < 1.0 {
+            env.problem("RATE_LIMIT_BURST must be at least 1");
+        }
+        let channel_capacity = env.parse("CHANNEL_CAPACITY", 256);
+        if channel_capacity == 0 {
+            env.problem("CHANNEL_CAPACITY must be greater than zero");
+        }
+        let slow_consumer_high_water = env.parse("SLOW_CONSUMER_HIGH_WATER", (channel_capacity * 3 / 4).max(1));
+        if slow_consumer_high_water == 0 || slow_consumer_high_water > channel_capacity {
+            env.problem("SLOW_CONSUMER_HIGH_WATER must be between 1 and CHANNEL_CAPACITY");
+        }
+
+        let max_connections = env.parse("MAX_CONNECTIONS", 10_000);
+        if max_connections == 0 {
+            env.problem("MAX_CONNECTIONS must be greater than zero");
+        }
+        let max_connections_per_user = env.parse("MAX_CONNECTIONS_PER_USER", 10);
+        if max_connections_per_user == 0 {
+            env.problem("MAX_CONNECTIONS_PER_USER must be greater than zero");
+        }
+        let max_auth_attempts = env.parse("MAX_AUTH_ATTEMPTS", 5);
+        if max_auth_attempts == 0 {
+            env.problem("MAX_AUTH_ATTEMPTS must be greater than zero");
+        }
+
+        let connect_rate_window = env.secs("CONNECT_RATE_WINDOW_SECS", 10);
+        if connect_rate_window.is_zero() {
+            env.problem("CONNECT_RATE_WINDOW_SECS must be greater than zero");
+        }
+
+        let max_payload_bytes = env.parse("MAX_PAYLOAD_BYTES", 64 * 1024);
+        if max_payload_bytes == 0 {
+            env.problem("MAX_PAYLOAD_BYTES must be greater than zero");
+        }
+        // Room for the envelope (type, target_id, msg_id, ...) around a maximum-size `data`
+        let max_message_bytes = env.parse("MAX_MESSAGE_BYTES", max_payload_bytes + 4 * 1024);
+        if max_message_bytes < max_payload_bytes {
+            env.problem("MAX_MESSAGE_BYTES must be at least MAX_PAYLOAD_BYTES");
+        }
+        let max_frame_bytes = env.parse("MAX_FRAME_BYTES", max_message_bytes);
+        if max_frame_bytes == 0 || max_frame_bytes > max_message_bytes {
+            env.problem("MAX_FRAME_BYTES must be between 1 and MAX_MESSAGE_BYTES");
+        }
+
+        let max_room_members = env.parse("MAX_ROOM_MEMBERS", 8);
+        if max_room_members < 2 {
+            env.problem("MAX_ROOM_MEMBERS must be at least 2");
+        }
+        let max_rooms_per_user = env.parse("MAX_ROOMS_PER_USER", 16);
+        if max_rooms_per_user == 0 {
+            env.problem("MAX_ROOMS_PER_USER must be at least 1");
+        }
+        let max_subscriptions_per_user = env.parse("MAX_SUBSCRIPTIONS_PER_USER", 1000);
+        if max_subscriptions_per_user == 0 {
+            env.problem("MAX_SUBSCRIPTIONS_PER_USER must be at least 1");
+        }
+
+        let shutdown_grace = env.secs("SHUTDOWN_GRACE_SECS", 10);
+        // Spreading reconnects past the grace period would leave clients on a closed socket
+        let reconnect_spread =
+            Duration::from_millis(env.parse("RECONNECT_SPREAD_MS", shutdown_grace.as_millis().min(5000) as u64));
+        if reconnect_spread > shutdown_grace {
+            env.problem("RECONNECT_SPREAD_MS must not exceed SHUTDOWN_GRACE_SECS");
+        }
+
+        let dead_letter_path = env.optional("DEAD_LETTER_PATH");
+        let dead_letter_url = env.optional("DEAD_LETTER_URL");
+        if dead_letter_path.is_some() && dead_letter_url.is_some() {
+            env.problem("DEAD_LETTER_PATH and DEAD_LETTER_URL are mutually exclusive");
+        }
+
+        let relay_nodes = env.list("RELAY_NODES", &[]);
+        let relay_secret = env.optional("RELAY_SECRET");
+        if !relay_nodes.is_empty() && relay_secret.is_none() {
+            env.problem("RELAY_SECRET must be set when RELAY_NODES is configured");
+        }
+
+        let cors_origins: Vec<String> = env
+            .list("CORS_ALLOWED_ORIGINS", &[])
+            .into_iter()
+            .map(|origin| origin.trim_end_matches('/').to_owned())
+            .collect();
+        if cors_origins.iter().any(|origin| origin != "*" && HeaderValue::from_str(origin).is_err()) {
+            env.problem("CORS_ALLOWED_ORIGINS contains an invalid origin");
+        }
+        let mut cors_methods = Vec::new();
+        for method in env.list("CORS_ALLOWED_METHODS", &["GET", "POST"]) {
+            match method.to_ascii_uppercase().parse::<Method>() {
+                Ok(method) => cors_methods.push(method),
+                Err(_) => env.problem(format!("CORS_ALLOWED_METHODS has an invalid method: {}", method)),
+            }
+        }
+
+        let friendship_check = env.flag("FRIENDSHIP_CHECK");
+        let koda_api_url = env.optional("KODA_API_URL");
+        if friendship_check && koda_api_url.is_none() {
+            env.problem("KODA_API_URL must be set when FRIENDSHIP_CHECK is enabled");
+        }
+
+        let presence_ttl = env.secs("PRESENCE_TTL_SECS", 60);
+        if presence_ttl < Duration::from_secs(3) {
+            env.problem("PRESENCE_TTL_SECS must be at least 3");
+        }
+
+        let turn_urls = env.list("TURN_URLS", &[]);
+        let turn_secret = env.optional("TURN_SECRET");
+        if !turn_urls.is_empty() && turn_secret.is_none() {
+            env.problem("TURN_SECRET must be set when TURN_URLS is configured");
+        }
+
+        let tls_cert_path = env.optional("TLS_CERT_PATH");
+        let tls_key_path = env.optional("TLS_KEY_PATH");
+        if tls_cert_path.is_some() != tls_key_path.is_some() {
+            env.problem("TLS_CERT_PATH and TLS_KEY_PATH must be set together");
+        }
+
+        let bind_addr = match env.optional("BIND_ADDR") {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                env.problem(format!("BIND_ADDR must be an ip:port socket address (e.g. 0.0.0.0:3000), got {}", value));
+                DEFAULT_BIND_ADDR
+            }),
+            None => DEFAULT_BIND_ADDR,
+        };
+
+        let config = Config {
+            bind_addr,
+            tls_cert_path,
+            tls_key_path,
+            jwt_algorithm,
+            jwt_secret,
+            jwt_public_key_pem,
+            jwks_url,
+            jwks_refresh,
+            jwt_leeway: env.secs("JWT_LEEWAY_SECS", 30),
+            jwt_subject_claim: env.optional("JWT_SUBJECT_CLAIM").unwrap_or_else(|| "sub".to_owned()),
+            jwt_audience: env.list("JWT_AUDIENCE", &[]),
+            jwt_issuer: env.list("JWT_ISSUER", &[]),
+            signal_data_keys: env.list("SIGNAL_DATA_KEYS", &[]),
+            allowed_origins: env
+                .list("ALLOWED_ORIGINS", &["*"])
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_owned())
+                .collect(),
+            cors_origins,
+            cors_methods,
+            ping_interval,
+            ping_jitter,
+            pong_timeout,
+            send_timeout,
+            send_flush_grace: Duration::from_millis(env.parse("SEND_FLUSH_GRACE_MS", 1000)),
+            identify_timeout: env.secs("IDENTIFY_TIMEOUT_SECS", 10),
+            idle_timeout: env.secs("IDLE_TIMEOUT_SECS", 30 * 60),
+            max_connection_lifetime: env.secs("MAX_CONNECTION_LIFETIME_SECS", 0),
+            resume_ttl: env.secs("RESUME_TTL_SECS", 120),
+            rate_limit_per_sec,
+            rate_limit_burst,
+            channel_capacity,
+            slow_consumer_high_water,
+            slow_consumer_disconnect: env.flag("SLOW_CONSUMER_DISCONNECT"),
+            max_connections,
+            connect_rate_limit: env.parse("CONNECT_RATE_LIMIT", 20),
+            connect_rate_window,
+            trust_forwarded_for: env.flag("TRUST_FORWARDED_FOR"),
+            require_secure: env.flag("REQUIRE_SECURE"),
+            max_connections_per_user,
+            max_auth_attempts,
+            max_payload_bytes,
+            signal_dedup_window: Duration::from_millis(env.parse("SIGNAL_DEDUP_WINDOW_MS", 0)),
+            max_message_bytes,
+            max_frame_bytes,
+            max_room_members,
+            max_rooms_per_user,
+            max_subscriptions_per_user,
+            shutdown_grace,
+            reconnect_spread,
+            shutdown_redirect_url: env.optional("SHUTDOWN_REDIRECT_URL"),
+            // Off by default: with queueing enabled, senders no longer get an immediate PEER_OFFLINE
+            offline_queue_depth: env.parse("OFFLINE_QUEUE_DEPTH", 0),
+            offline_queue_ttl: env.secs("OFFLINE_QUEUE_TTL_SECS", 30),
+            daily_signal_quota: env.parse("DAILY_SIGNAL_QUOTA", 0),
+            friendship_check,
+            dead_letter_path,
+            dead_letter_url,
+            dead_letter_include_data: env.flag("DEAD_LETTER_INCLUDE_DATA"),
+            event_webhook_url: env.optional("EVENT_WEBHOOK_URL"),
+            relay_nodes,
+            relay_secret,
+            koda_api_url,
+            koda_api_token: env.optional("KODA_API_TOKEN"),
+            friendship_cache_ttl: env.secs("FRIENDSHIP_CACHE_TTL_SECS", 60),
+            handle_cache_ttl: env.secs("HANDLE_CACHE_TTL_SECS", 30),
+            reveal_blocks: env.flag("REVEAL_BLOCKS"),
+            redis_url: env.optional("REDIS_URL"),
+            node_id: env.optional("NODE_ID").unwrap_or_else(|| Uuid::new_v4().to_string()),
+            presence_ttl,
+            last_seen_horizon: env.secs("LAST_SEEN_HORIZON_SECS", 7 * 24 * 60 * 60),
+            presence_grace: env.secs("PRESENCE_GRACE_SECS", 0),
+            admin_token: env.optional("ADMIN_TOKEN"),
+            stun_urls: env.list("STUN_URLS", &[]),
+            turn_urls,
+            turn_secret,
+            turn_credential_ttl: env.secs("TURN_CREDENTIAL_TTL_SECS", 86_400),
+        };
+
+        if env.problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(env.problems))
+        }
+    }
+}
+
+struct EnvReader<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    problems: Vec<String>,
+}
+
+impl EnvReader<'_> {
+    fn problem(&mut self, message: impl Into<String>) {
+        self.problems.push(message.into());
+    }
+
+    fn optional(&mut self, key: &str) -> Option<String> {
+        (self.lookup)(key).filter(|value| !value.is_empty())
+    }
+
+    fn parse<T: FromStr>(&mut self, key: &str, default: T) -> T {
+        match self.optional(key) {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                self.problem(format!("{} has an invalid value: {}", key, value));
+                default
+            }),
+            None => default,
+        }
+    }
+
+    fn secs(&mut self, key: &str, default: u64) -> Duration {
+        Duration::from_secs(self.parse(key, default))
+    }
+
+    fn flag(&mut self, key: &str) -> bool {
+        match self.optional(key).as_deref() {
+            None | Some("false") | Some("0") => false,
+            Some("true") | Some("1") => true,
+            Some(other) => {
+                self.problem(format!("{} must be true or false, got {}", key, other));
+                false
+            }
+        }
+    }
+
+    fn list(&mut self, key: &str, default: &[&str]) -> Vec<String> {
+        match self.optional(key) {
+            Some(value) => value
+                .split(',')
+                .map(|item| item.trim().to_owned())
+                .filter(|item| !item.is_empty())
+                .collect(),
+            None => default.iter().map(|item| item.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with(settings: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        Config::from_lookup(|key| settings.iter().find(|(name, _)| *name == key).map(|(_, value)| value.to_string()))
+    }
+
+    #[test]
+    fn empty_or_short_jwt_secret_is_rejected() {
+        for secret in ["", "too-short"] {
+            let error = config_with(&[("JWT_SECRET", secret)]).err().expect("weak secret must be rejected");
+            assert!(error.to_string().contains("JWT_SECRET"), "{}", error);
+        }
+        assert!(config_with(&[("JWT_SECRET", &"k".repeat(32))]).is_ok());
+    }
+
+    #[test]
+    fn jwt_secret_must_match_the_hash_size() {
+        let secret = "k".repeat(32);
+        assert!(config_with(&[("JWT_SECRET", &secret), ("JWT_ALG", "HS512")]).is_err());
+        assert!(config_with(&[("JWT_SECRET", &"k".repeat(64)), ("JWT_ALG", "HS512")]).is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+use axum::http::HeaderMap;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Sliding window of recent upgrade attempts per client IP
+pub struct ConnectLimiter {
+    attempts: DashMap<IpAddr, VecDeque<Instant>>,
+    window: Duration,
+    limit: usize,
+}
+
+impl ConnectLimiter {
+    /// A `limit` of zero disables the check entirely.
+    pub fn new(window: Duration, limit: usize) -> Self {
+        ConnectLimiter { attempts: DashMap::new(), window, limit }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Records an attempt from `ip`; false if it already made `limit` attempts within the window.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let now = Instant::now();
+        let mut attempts = self.attempts.entry(ip).or_default();
+        self.drop_expired(&mut attempts, now);
+        if attempts.len() >= self.limit {
+            return false;
+        }
+        attempts.push_back(now);
+        true
+    }
+
+    // IPs that stop connecting would otherwise keep their entry forever
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.attempts.retain(|_, attempts| {
+            self.drop_expired(attempts, now);
+            !attempts.is_empty()
+        });
+    }
+
+    fn drop_expired(&self, attempts: &mut VecDeque<Instant>, now: Instant) {
+        while attempts.front().is_some_and(|&at| now.duration_since(at) >= self.window) {
+            attempts.pop_front();
+        }
+    }
+}
+
+/// The socket's peer address, or the address our proxy appended to `X-Forwarded-For` when trusted.
+pub fn client_ip(peer: SocketAddr, headers: &HeaderMap, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for {
+        // Only the last hop was added by our proxy; anything before it is client-supplied
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .next_back();
+        if let Some(Ok(ip)) = forwarded.map(|hop| hop.trim().parse()) {
+            return ip;
+        }
+    }
+    peer.ip()
+}
@@ -0,0 +1,165 @@
+use metrics::counter;
+use std::fs::OpenOptions;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::protocol::KodaSignal;
+
+// Records waiting for the writer; beyond this they are dropped rather than slowing routing down
+const BACKLOG: usize = 1024;
+
+/// Records routed messages that never reached their target, for working out afterwards why a call
+/// failed to connect. Written off the routing path to a JSON-lines file or a webhook.
+pub struct DeadLetters {
+    tx: mpsc::Sender<String>,
+    include_data: bool,
+}
+
+enum Sink {
+    File(tokio::fs::File),
+    Webhook { client: reqwest::Client, url: String },
+}
+
+impl DeadLetters {
+    /// Returns None unless `DEAD_LETTER_PATH` or `DEAD_LETTER_URL` is set.
+    /// Panics if the file can't be opened, so a typo stops the node at startup.
+    pub fn new(config: &Config) -> Option<Self> {
+        let sink = if let Some(path) = &config.dead_letter_path {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|e| panic!("Cannot open DEAD_LETTER_PATH {}: {}", path, e));
+            Sink::File(tokio::fs::File::from_std(file))
+        } else {
+            Sink::Webhook {
+                client: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(5))
+                    .build()
+                    .expect("failed to build HTTP client"),
+                url: config.dead_letter_url.clone()?,
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        tokio::spawn(sink.run(rx));
+        Some(DeadLetters { tx, include_data: config.dead_letter_include_data })
+    }
+
+    /// Queues a record of `routed`, which was dropped for `reason`. Only signals, hangups and
+    /// candidate batches are recorded.
+    pub fn record_routed(&self, reason: &str, routed: &KodaSignal) {
+        match routed {
+            KodaSignal::Signal { sender_id: Some(sender_id), target_id, data, .. } => {
+                self.record(reason, "SIGNAL", *sender_id, *target_id, Some(data))
+            }
+            KodaSignal::Hangup { sender_id: Some(sender_id), target_id, .. } => {
+                self.record(reason, "HANGUP", *sender_id, *target_id, None)
+            }
+            KodaSignal::CandidateBatch { sender_id: Some(sender_id), target_id, .. } => {
+                self.record(reason, "CANDIDATE_BATCH", *sender_id, *target_id, None)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn record(&self, reason: &str, kind: &str, sender_id: Uuid, target_id: Uuid, data: Option<&serde_json::Value>) {
+        let mut record = serde_json::json!({
+            "ts": crate::unix_millis(),
+            "reason": reason,
+            "type": kind,
+            "sender_id": sender_id,
+            "target_id": target_id,
+        });
+        // Signal payloads hold SDP with IP addresses, so they are left out unless asked for
+        if self.include_data
+            && let Some(data) = data
+        {
+            record["data"] = data.clone();
+        }
+        if self.tx.try_send(record.to_string()).is_err() {
+            counter!("koda_dead_letters_dropped_total").increment(1);
+        }
+    }
+}
+
+impl Sink {
+    async fn run(mut self, mut rx: mpsc::Receiver<String>) {
+        while let Some(record) = rx.recv().await {
+            match &mut self {
+                Sink::File(file) => {
+                    let line = record + "\n";
+                    // Flushed per record so nothing is lost if the node is killed right after a failed call
+                    let written = async {
+                        file.write_all(line.as_bytes()).await?;
+                        file.flush().await
+                    };
+                    if let Err(e) = written.await {
+                        warn!(error = %e, "Failed to write dead letter");
+                    }
+                }
+                Sink::Webhook { client, url } => {
+                    let posted = client
+                        .post(url.as_str())
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .body(record)
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status());
+                    if let Err(e) = posted {
+                        warn!(error = %e, "Failed to post dead letter");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dead_letters(include_data: bool) -> (DeadLetters, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel(4);
+        (DeadLetters { tx, include_data }, rx)
+    }
+
+    fn signal(sender_id: Uuid, target_id: Uuid) -> KodaSignal {
+        KodaSignal::Signal {
+            target_id,
+            sender_id: Some(sender_id),
+            data: serde_json::json!({ "sdp": "v=0 c=IN IP4 203.0.113.7" }),
+            msg_id: None,
+            seq: None,
+            server_ts: None,
+            want_status: None,
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn records_carry_reason_and_peers_but_not_data_by_default() {
+        let (sender, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let (letters, mut rx) = dead_letters(false);
+        letters.record_routed("peer_offline", &signal(sender, target));
+
+        let record: serde_json::Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(record["reason"], "peer_offline");
+        assert_eq!(record["type"], "SIGNAL");
+        assert_eq!(record["sender_id"], sender.to_string());
+        assert_eq!(record["target_id"], target.to_string());
+        assert!(record.get("data").is_none(), "data must be redacted: {}", record);
+    }
+
+    #[test]
+    fn data_is_included_when_enabled() {
+        let (letters, mut rx) = dead_letters(true);
+        letters.record_routed("peer_busy", &signal(Uuid::new_v4(), Uuid::new_v4()));
+        let record: serde_json::Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(record["data"]["sdp"], "v=0 c=IN IP4 203.0.113.7");
+    }
+}
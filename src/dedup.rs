@@ -0,0 +1,94 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Plenty for a burst of ICE candidates; the oldest entry is forgotten first
+const CAPACITY: usize = 64;
+
+/// Suppresses a connection's identical signals to the same target within a short window.
+///
+/// Owned by the connection's read loop like the Sequencer. A zero window disables it.
+#[derive(Default)]
+pub struct Dedup {
+    window: Duration,
+    // (fingerprint, first seen), oldest first
+    recent: VecDeque<(u64, Instant)>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Self {
+        Dedup { window, recent: VecDeque::new() }
+    }
+
+    /// True if the same `data` went to `target_id` within the window.
+    pub fn is_duplicate(&mut self, target_id: Uuid, data: &serde_json::Value, now: Instant) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+        while self.recent.front().is_some_and(|&(_, seen)| now.duration_since(seen) >= self.window) {
+            self.recent.pop_front();
+        }
+        let fingerprint = fingerprint(target_id, data);
+        self.recent.iter().any(|&(seen, _)| seen == fingerprint)
+    }
+
+    /// Records a signal that was let through, so identical ones within the window are duplicates.
+    /// Kept apart from `is_duplicate` so a signal refused for another reason can be retried.
+    pub fn remember(&mut self, target_id: Uuid, data: &serde_json::Value, now: Instant) {
+        if self.window.is_zero() {
+            return;
+        }
+        let fingerprint = fingerprint(target_id, data);
+        if self.recent.len() == CAPACITY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((fingerprint, now));
+    }
+}
+
+fn fingerprint(target_id: Uuid, data: &serde_json::Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    target_id.hash(&mut hasher);
+    data.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const WINDOW: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn identical_signal_within_window_is_suppressed() {
+        let mut dedup = Dedup::new(WINDOW);
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        let candidate = json!({ "candidate": "udp 1 10.0.0.1 5000" });
+        assert!(!dedup.is_duplicate(target, &candidate, now));
+        dedup.remember(target, &candidate, now);
+        assert!(dedup.is_duplicate(target, &candidate, now + Duration::from_millis(100)));
+        // Different data, or the same data to someone else, is not a repeat
+        assert!(!dedup.is_duplicate(target, &json!({ "candidate": "udp 2 10.0.0.1 5001" }), now));
+        assert!(!dedup.is_duplicate(Uuid::new_v4(), &candidate, now));
+    }
+
+    #[test]
+    fn signal_is_forwarded_again_once_the_window_expires() {
+        let mut dedup = Dedup::new(WINDOW);
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        let candidate = json!({ "candidate": "udp 1 10.0.0.1 5000" });
+        dedup.remember(target, &candidate, now);
+        assert!(!dedup.is_duplicate(target, &candidate, now + WINDOW));
+    }
+
+    #[test]
+    fn zero_window_disables_suppression() {
+        let mut dedup = Dedup::default();
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        dedup.remember(target, &json!({}), now);
+        assert!(!dedup.is_duplicate(target, &json!({}), now));
+    }
+}
@@ -0,0 +1,108 @@
+use metrics::counter;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+// Events waiting for the poster; beyond this they are dropped rather than slowing connects down
+const BACKLOG: usize = 4096;
+// A reconnect storm becomes a handful of large POSTs instead of one per socket
+const BATCH_WINDOW: Duration = Duration::from_secs(1);
+const MAX_BATCH: usize = 500;
+const ATTEMPTS: u32 = 3;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Serialize)]
+struct Event {
+    user_id: Uuid,
+    event: ConnectionEvent,
+    timestamp: i64,
+    // The user's devices on this node once the event has happened
+    connection_count: usize,
+}
+
+/// Tells an outside service when users connect and disconnect, so it needn't parse our logs.
+/// Events are batched and posted off the connection path; delivery is best effort.
+pub struct EventWebhook {
+    tx: mpsc::Sender<Event>,
+}
+
+impl EventWebhook {
+    /// Returns None unless `EVENT_WEBHOOK_URL` is set.
+    pub fn new(config: &Config) -> Option<Self> {
+        let url = config.event_webhook_url.clone()?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("failed to build HTTP client");
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        tokio::spawn(post_batches(client, url, rx));
+        Some(EventWebhook { tx })
+    }
+
+    pub fn record(&self, user_id: Uuid, event: ConnectionEvent, connection_count: usize) {
+        let event = Event { user_id, event, timestamp: crate::unix_millis(), connection_count };
+        if self.tx.try_send(event).is_err() {
+            counter!("koda_connection_events_dropped_total").increment(1);
+        }
+    }
+}
+
+async fn post_batches(client: reqwest::Client, url: String, mut rx: mpsc::Receiver<Event>) {
+    let mut batch = Vec::new();
+    while let Some(first) = rx.recv().await {
+        batch.push(first);
+        // Let the rest of a burst arrive before posting
+        time::sleep(BATCH_WINDOW).await;
+        while batch.len() < MAX_BATCH
+            && let Ok(event) = rx.try_recv()
+        {
+            batch.push(event);
+        }
+        post(&client, &url, &batch).await;
+        batch.clear();
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &str, batch: &[Event]) {
+    for attempt in 1..=ATTEMPTS {
+        let posted = client.post(url).json(batch).send().await.and_then(|response| response.error_for_status());
+        match posted {
+            Ok(_) => return,
+            Err(e) if attempt == ATTEMPTS => {
+                warn!(error = %e, events = batch.len(), "Failed to post connection events, dropping them");
+                counter!("koda_connection_events_dropped_total").increment(batch.len() as u64);
+            }
+            Err(_) => time::sleep(Duration::from_millis(250 << attempt)).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_carry_user_kind_time_and_device_count() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let webhook = EventWebhook { tx };
+        let user_id = Uuid::new_v4();
+        webhook.record(user_id, ConnectionEvent::Disconnected, 1);
+
+        let event = serde_json::to_value(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(event["user_id"], user_id.to_string());
+        assert_eq!(event["event"], "disconnected");
+        assert_eq!(event["connection_count"], 1);
+        assert!(event["timestamp"].as_i64().is_some_and(|ts| ts > 0));
+    }
+}
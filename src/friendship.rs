@@ -0,0 +1,100 @@
+use dashmap::DashMap;
+use reqwest::StatusCode;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+// Asks koda-api whether two users are friends, caching answers so routing stays fast
+pub struct FriendshipChecker {
+    client: reqwest::Client,
+    api_url: String,
+    api_token: Option<String>,
+    cache: DashMap<(Uuid, Uuid), (Instant, bool)>,
+    ttl: Duration,
+}
+
+impl FriendshipChecker {
+    /// Returns None when `FRIENDSHIP_CHECK` is off so local dev needs no API.
+    pub fn new(config: &Config) -> Option<Self> {
+        if !config.friendship_check {
+            return None;
+        }
+        let api_url = config.koda_api_url.as_deref()?;
+
+        Some(FriendshipChecker {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(2))
+                .build()
+                .expect("failed to build HTTP client"),
+            api_url: api_url.trim_end_matches('/').to_owned(),
+            api_token: config.koda_api_token.clone(),
+            cache: DashMap::new(),
+            ttl: config.friendship_cache_ttl,
+        })
+    }
+
+    /// Fails closed: if koda-api can't be reached the pair is treated as not friends (uncached).
+    pub async fn are_friends(&self, a: Uuid, b: Uuid) -> bool {
+        // Friendship is symmetric, so both directions share one cache entry
+        let key = if a < b { (a, b) } else { (b, a) };
+        if let Some(entry) = self.cache.get(&key) {
+            let (checked_at, friends) = *entry;
+            if checked_at.elapsed() < self.ttl {
+                return friends;
+            }
+        }
+
+        let url = format!("{}/internal/friendships/{}/{}", self.api_url, key.0, key.1);
+        let mut request = self.client.get(&url);
+        if let Some(token) = &self.api_token {
+            request = request.bearer_auth(token);
+        }
+
+        let friends = match request.send().await {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => false,
+            Ok(response) => {
+                warn!(status = %response.status(), "Friendship lookup failed");
+                return false;
+            }
+            Err(e) => {
+                warn!(error = %e, "Friendship lookup failed");
+                return false;
+            }
+        };
+        self.cache.insert(key, (Instant::now(), friends));
+        friends
+    }
+
+    // Clients choose the targets, so expired answers mustn't pile up
+    pub fn prune(&self) {
+        self.cache.retain(|_, (checked_at, _)| checked_at.elapsed() < self.ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_drops_only_expired_answers() {
+        let checker = FriendshipChecker {
+            client: reqwest::Client::new(),
+            api_url: "http://koda-api.test".to_owned(),
+            api_token: None,
+            cache: DashMap::new(),
+            ttl: Duration::from_secs(60),
+        };
+        let (fresh, stale) = ((Uuid::new_v4(), Uuid::new_v4()), (Uuid::new_v4(), Uuid::new_v4()));
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(120)).unwrap();
+        checker.cache.insert(fresh, (Instant::now(), true));
+        checker.cache.insert(stale, (long_ago, false));
+
+        checker.prune();
+        assert!(checker.cache.contains_key(&fresh));
+        assert!(!checker.cache.contains_key(&stale));
+    }
+}
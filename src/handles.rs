@@ -0,0 +1,92 @@
+use dashmap::DashMap;
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+// Longer than any username or email koda-api accepts, so not worth a lookup
+const MAX_HANDLE_LEN: usize = 256;
+
+// Resolves external handles (username or email) to user ids through koda-api, caching answers
+pub struct HandleResolver {
+    client: reqwest::Client,
+    api_url: Url,
+    api_token: Option<String>,
+    // handle -> when it was looked up and whom it names, None if nobody
+    cache: DashMap<String, (Instant, Option<Uuid>)>,
+    ttl: Duration,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: Uuid,
+}
+
+impl HandleResolver {
+    /// Returns None without `KODA_API_URL`; handles then never resolve.
+    /// Panics if the URL can't be parsed, so a typo stops the node at startup.
+    pub fn new(config: &Config) -> Option<Self> {
+        let api_url = config.koda_api_url.as_deref()?;
+        Some(HandleResolver {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(2))
+                .build()
+                .expect("failed to build HTTP client"),
+            api_url: Url::parse(api_url).unwrap_or_else(|e| panic!("Invalid KODA_API_URL {}: {}", api_url, e)),
+            api_token: config.koda_api_token.clone(),
+            cache: DashMap::new(),
+            ttl: config.handle_cache_ttl,
+        })
+    }
+
+    /// None if koda-api knows no such user. Lookup failures are None too, but aren't cached.
+    pub async fn resolve(&self, handle: &str) -> Option<Uuid> {
+        if handle.is_empty() || handle.len() > MAX_HANDLE_LEN {
+            return None;
+        }
+        if let Some(entry) = self.cache.get(handle) {
+            let (resolved_at, user_id) = *entry;
+            if resolved_at.elapsed() < self.ttl {
+                return user_id;
+            }
+        }
+
+        // Pushed as a path segment so handles with `/`, `@` or `?` are escaped
+        let mut url = self.api_url.clone();
+        url.path_segments_mut().ok()?.pop_if_empty().extend(["internal", "users", "by-handle", handle]);
+        let mut request = self.client.get(url);
+        if let Some(token) = &self.api_token {
+            request = request.bearer_auth(token);
+        }
+
+        let user_id = match request.send().await {
+            Ok(response) if response.status().is_success() => match response.json::<User>().await {
+                Ok(user) => Some(user.id),
+                Err(e) => {
+                    warn!(error = %e, "Handle lookup returned an unreadable user");
+                    return None;
+                }
+            },
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => None,
+            Ok(response) => {
+                warn!(status = %response.status(), "Handle lookup failed");
+                return None;
+            }
+            Err(e) => {
+                warn!(error = %e, "Handle lookup failed");
+                return None;
+            }
+        };
+        self.cache.insert(handle.to_owned(), (Instant::now(), user_id));
+        user_id
+    }
+
+    // Clients choose the handles, so stale answers mustn't pile up
+    pub fn prune(&self) {
+        self.cache.retain(|_, (resolved_at, _)| resolved_at.elapsed() < self.ttl);
+    }
+}
@@ -0,0 +1,21 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+
+use crate::AppState;
+
+// Probes read atomics only, never the peer map, so they are safe to hit every second
+pub async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    Json(json!({
+        "status": "ok",
+        "peers": state.online_users.load(Ordering::Relaxed),
+    }))
+}
+
+pub async fn ready(State(state): State<AppState>) -> impl IntoResponse {
+    if state.ready.load(Ordering::Relaxed) && !state.draining.load(Ordering::Relaxed) {
+        (StatusCode::OK, Json(json!({ "status": "ready" })))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "not_ready" })))
+    }
+}
@@ -0,0 +1,2627 @@
+mod admin;
+mod auth;
+mod blocklist;
+mod cluster;
+pub mod config;
+mod connect_limit;
+mod dead_letter;
+mod dedup;
+mod events;
+mod friendship;
+mod handles;
+mod health;
+mod offline_queue;
+mod presence;
+mod quota;
+pub mod protocol;
+mod resume;
+mod rate_limit;
+mod relay;
+mod rooms;
+mod sequencer;
+mod sink;
+mod telemetry;
+#[cfg(test)]
+mod test_tokens;
+mod turn;
+
+use axum::{
+    extract::{ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade}, ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{self, Instant};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use uuid::Uuid;
+use futures::{sink::SinkExt, stream::{SplitSink, StreamExt}, FutureExt};
+use serde::Serialize;
+use auth::{Claims, JwtVerifier};
+use axum_server::tls_rustls::RustlsConfig;
+use blocklist::Blocklist;
+use cluster::Cluster;
+use relay::HttpRelay;
+use config::Config;
+use connect_limit::ConnectLimiter;
+use dead_letter::DeadLetters;
+use events::{ConnectionEvent, EventWebhook};
+use dedup::Dedup;
+use friendship::FriendshipChecker;
+use handles::HandleResolver;
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind as JwtErrorKind};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tracing::{debug, error, info, trace, warn, Instrument};
+use turn::IceConfig;
+use offline_queue::OfflineQueue;
+use presence::Presence;
+use quota::SignalQuota;
+use protocol::{close_codes, DeliveryStatus, ErrorCode, KodaSignal, Limits, PresenceStatus, Priority, ProtocolVersion, CLIENT_MESSAGE_TYPES};
+use rate_limit::TokenBucket;
+use resume::ResumeTokens;
+use rooms::{Join, Rooms};
+use sequencer::Sequencer;
+use sink::PeerSink;
+
+// Upper bound on ids per WHO_IS_ONLINE so a single query can't walk the whole map
+const MAX_PRESENCE_QUERY: usize = 256;
+// Each MULTI_SIGNAL target costs a full route, so one message mustn't fan out without limit
+const MAX_MULTI_SIGNAL_TARGETS: usize = 16;
+// Enough for a full gathering phase; the batch still has to fit MAX_PAYLOAD_BYTES
+const MAX_CANDIDATE_BATCH: usize = 32;
+// Presence updates for more subscribers than this go out from their own task, a chunk at a time
+const PRESENCE_FANOUT_CHUNK: usize = 256;
+// Between chunks, so a popular user's login can't flood every socket's queue at once
+const PRESENCE_FANOUT_PAUSE: Duration = Duration::from_millis(5);
+// Close a little before `exp` so nothing is routed on a token the API already considers dead
+const SESSION_EXPIRY_SKEW: Duration = Duration::from_secs(5);
+const MAX_USER_AGENT_LEN: usize = 256;
+// Characters, not bytes; enough for "In a meeting until 3pm" with room to spare
+const MAX_STATUS_TEXT_LEN: usize = 128;
+const MAX_DISCONNECT_REASON_LEN: usize = 64;
+
+// Use DashMap for high-performance concurrent access in Switzerland
+// Each user maps to every live socket they hold (one per device)
+type PeerMap = Arc<DashMap<Uuid, Vec<PeerConnection>>>;
+
+#[derive(Clone)]
+struct PeerConnection {
+    connection_id: Uuid,
+    tx: Arc<dyn PeerSink>,
+    framing: Framing,
+    // Cancelled to tear the socket down from outside, even if its queue is full
+    kicked: CancellationToken,
+    info: Arc<ConnectionInfo>,
+}
+
+/// Captured at upgrade time for support and abuse investigations.
+#[derive(Debug, Serialize)]
+struct ConnectionInfo {
+    client_ip: IpAddr,
+    // Truncated; clients control it
+    user_agent: Option<String>,
+    // Unix millis
+    connected_at: i64,
+    // Negotiated at upgrade; handlers branch on it once the wire format changes
+    protocol: ProtocolVersion,
+    #[serde(flatten)]
+    traffic: Traffic,
+}
+
+// Text and binary messages only; WebSocket control frames aren't counted
+#[derive(Debug, Default, Serialize)]
+struct Traffic {
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    // Unix millis of the latest inbound message, 0 before the first
+    last_activity: AtomicI64,
+}
+
+impl Traffic {
+    fn received(&self, bytes: usize) {
+        self.last_activity.store(unix_millis(), Ordering::Relaxed);
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        counter!("koda_ws_messages_total", "direction" => "in").increment(1);
+        counter!("koda_ws_bytes_total", "direction" => "in").increment(bytes as u64);
+    }
+
+    fn sent(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        counter!("koda_ws_messages_total", "direction" => "out").increment(1);
+        counter!("koda_ws_bytes_total", "direction" => "out").increment(bytes as u64);
+    }
+}
+
+// Both framings carry the same JSON; a connection is answered in the framing it first used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    Text,
+    Binary,
+}
+
+impl Framing {
+    fn wrap(self, text: String) -> Message {
+        match self {
+            Framing::Text => Message::Text(text.into()),
+            Framing::Binary => Message::Binary(text.into_bytes().into()),
+        }
+    }
+}
+
+impl PeerConnection {
+    /// Tells the client why, then closes the socket.
+    fn kick(&self, code: ErrorCode) {
+        close_with_error(self, code);
+        self.kicked.cancel();
+    }
+
+    // Replies to a client that isn't draining its own queue are simply dropped
+    fn send(&self, signal: &KodaSignal) {
+        if let Some(text) = to_json(signal) {
+            let _ = self.send_text(&text);
+        }
+    }
+
+    fn send_text(&self, text: &str) -> Result<(), TrySendError<Message>> {
+        self.tx.try_send(self.framing.wrap(text.to_owned()))
+    }
+
+    // Lower priorities stop short of a full queue, keeping the remaining slots for call setup
+    fn has_room_for(&self, priority: Priority) -> bool {
+        let capacity = self.tx.capacity();
+        let limit = match priority {
+            Priority::Low => capacity / 2,
+            Priority::Normal => capacity - capacity / 8,
+            Priority::High => capacity,
+        };
+        self.queue_depth() < limit.max(1)
+    }
+
+    /// Frames queued for this socket that the send task hasn't written yet.
+    fn queue_depth(&self) -> usize {
+        self.tx.queue_depth()
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    peers: PeerMap,
+    presence: Arc<Presence>,
+    blocklist: Arc<Blocklist>,
+    rooms: Arc<Rooms>,
+    connect_limiter: Arc<ConnectLimiter>,
+    offline_queue: Arc<OfflineQueue>,
+    quota: Arc<SignalQuota>,
+    friendships: Option<Arc<FriendshipChecker>>,
+    handles: Option<Arc<HandleResolver>>,
+    dead_letters: Option<Arc<DeadLetters>>,
+    events: Option<Arc<EventWebhook>>,
+    cluster: Option<Arc<Cluster>>,
+    // Sibling nodes reached over HTTP when the target isn't here and Redis can't place them
+    relay: Option<Arc<HttpRelay>>,
+    ice: Arc<IceConfig>,
+    resume: Arc<ResumeTokens>,
+    jwt: JwtVerifier,
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+    connections: TaskTracker,
+    online_users: Arc<AtomicUsize>,
+    open_connections: Arc<AtomicUsize>,
+    ready: Arc<AtomicBool>,
+    // Set by /admin/drain: existing sockets stay, new ones are turned away
+    draining: Arc<AtomicBool>,
+    metrics: PrometheusHandle,
+}
+
+impl AppState {
+    /// A node without cross-node routing; `run` attaches the Redis cluster when configured.
+    pub fn new(config: Config, metrics: PrometheusHandle) -> Self {
+        AppState {
+            peers: Arc::new(DashMap::new()),
+            presence: Arc::new(Presence::default()),
+            blocklist: Arc::new(Blocklist::default()),
+            rooms: Arc::new(Rooms::default()),
+            connect_limiter: Arc::new(ConnectLimiter::new(config.connect_rate_window, config.connect_rate_limit)),
+            offline_queue: Arc::new(OfflineQueue::new(config.offline_queue_ttl, config.offline_queue_depth)),
+            quota: Arc::new(SignalQuota::new(config.daily_signal_quota)),
+            friendships: FriendshipChecker::new(&config).map(Arc::new),
+            handles: HandleResolver::new(&config).map(Arc::new),
+            dead_letters: DeadLetters::new(&config).map(Arc::new),
+            events: EventWebhook::new(&config).map(Arc::new),
+            cluster: None,
+            relay: HttpRelay::new(&config).map(Arc::new),
+            ice: Arc::new(IceConfig::new(&config)),
+            resume: Arc::new(ResumeTokens::new(&config)),
+            jwt: JwtVerifier::new(&config),
+            config: Arc::new(config),
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            online_users: Arc::new(AtomicUsize::new(0)),
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            ready: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            metrics,
+        }
+    }
+}
+
+/// Every route the node serves. Serve it with `into_make_service_with_connect_info::<SocketAddr>()`,
+/// since the per-IP connect limit needs the peer address.
+pub fn build_app(state: AppState) -> Router {
+    let mut http = Router::new()
+        .route("/health", get(health::health))
+        .route("/ready", get(health::ready))
+        .route("/metrics", get(telemetry::metrics))
+        .route("/admin/kick/{user_id}", post(admin::kick))
+        .route("/admin/connections/{user_id}", get(admin::connections))
+        .route("/admin/peers", get(admin::peers))
+        .route("/admin/registry", get(admin::registry))
+        .route("/admin/broadcast", post(admin::broadcast))
+        .route("/admin/drain", post(admin::drain))
+        .route("/admin/undrain", post(admin::undrain))
+        .route("/relay", post(relay::receive));
+    // Kept off `/pulse`, whose upgrades are checked against ALLOWED_ORIGINS instead
+    if let Some(cors) = cors_layer(&state.config) {
+        http = http.layer(cors);
+    }
+    Router::new().route("/pulse", get(ws_handler)).merge(http).with_state(state)
+}
+
+// For browser dashboards on another origin; without CORS_ALLOWED_ORIGINS browsers stay same-origin
+fn cors_layer(config: &Config) -> Option<CorsLayer> {
+    if config.cors_origins.is_empty() {
+        return None;
+    }
+    let origins = if config.cors_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        // Config::from_env has already rejected origins that aren't valid header values
+        AllowOrigin::list(config.cors_origins.iter().filter_map(|origin| origin.parse().ok()))
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(config.cors_methods.clone())
+            // The admin API authenticates with a Bearer token and takes JSON bodies
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]),
+    )
+}
+
+/// Runs the node until SIGINT/SIGTERM, then drains its sockets.
+pub async fn run(config: Config) {
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        warn!("ALLOWED_ORIGINS permits any origin to open WebSocket upgrades");
+    }
+
+    let cluster = match &config.redis_url {
+        Some(url) => {
+            let cluster = Cluster::connect(url, config.node_id.clone(), config.presence_ttl)
+                .await
+                .unwrap_or_else(|e| panic!("Cannot connect to REDIS_URL: {}", e));
+            info!(node_id = cluster.node_id(), "Cross-node routing enabled");
+            Some(Arc::new(cluster))
+        }
+        None => None,
+    };
+
+    let mut state = AppState::new(config, telemetry::install_recorder());
+    if let Some(cluster) = &cluster {
+        let config = &state.config;
+        state.offline_queue =
+            Arc::new(OfflineQueue::durable(config.offline_queue_ttl, config.offline_queue_depth, cluster.commands()));
+        state.quota = Arc::new(SignalQuota::durable(config.daily_signal_quota, cluster.commands()));
+    }
+    state.cluster = cluster;
+    if let Err(e) = state.jwt.refresh().await {
+        panic!("Cannot load JWKS from JWKS_URL: {}", e);
+    }
+    state.jwt.spawn_refresh(state.config.jwks_refresh);
+
+    if let Some(cluster) = &state.cluster {
+        cluster.clone().spawn(state.clone());
+    }
+
+    let presence = state.presence.clone();
+    let last_seen_horizon = state.config.last_seen_horizon;
+    tokio::spawn(async move {
+        let mut sweep = time::interval(Duration::from_secs(10 * 60));
+        loop {
+            sweep.tick().await;
+            presence.prune_last_seen(last_seen_horizon);
+        }
+    });
+
+    if state.resume.is_enabled() {
+        let resume = state.resume.clone();
+        tokio::spawn(async move {
+            let mut sweep = time::interval(Duration::from_secs(60));
+            loop {
+                sweep.tick().await;
+                resume.prune();
+            }
+        });
+    }
+
+    if state.connect_limiter.is_enabled() {
+        let connect_limiter = state.connect_limiter.clone();
+        tokio::spawn(async move {
+            let mut sweep = time::interval(connect_limiter.window());
+            loop {
+                sweep.tick().await;
+                connect_limiter.prune();
+            }
+        });
+    }
+
+    if let Some(friendships) = state.friendships.clone() {
+        tokio::spawn(async move {
+            let mut sweep = time::interval(Duration::from_secs(10 * 60));
+            loop {
+                sweep.tick().await;
+                friendships.prune();
+            }
+        });
+    }
+
+    if let Some(handles) = state.handles.clone() {
+        tokio::spawn(async move {
+            let mut sweep = time::interval(Duration::from_secs(10 * 60));
+            loop {
+                sweep.tick().await;
+                handles.prune();
+            }
+        });
+    }
+
+    let queues = state.clone();
+    tokio::spawn(async move {
+        let mut sweep = time::interval(Duration::from_secs(5));
+        loop {
+            sweep.tick().await;
+            check_outbound_queues(&queues);
+        }
+    });
+
+    if state.quota.is_enabled() {
+        let quota = state.quota.clone();
+        tokio::spawn(async move {
+            let mut sweep = time::interval(Duration::from_secs(10 * 60));
+            loop {
+                sweep.tick().await;
+                quota.prune();
+            }
+        });
+    }
+
+    if state.offline_queue.is_enabled() {
+        let offline_queue = state.offline_queue.clone();
+        tokio::spawn(async move {
+            let mut sweep = time::interval(Duration::from_secs(30));
+            loop {
+                sweep.tick().await;
+                offline_queue.prune();
+            }
+        });
+    }
+
+    let app = build_app(state.clone());
+    // Loaded before reporting ready so a bad certificate fails the deploy, not the first client
+    let tls = match (&state.config.tls_cert_path, &state.config.tls_key_path) {
+        (Some(cert), Some(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .unwrap_or_else(|e| panic!("Cannot load TLS_CERT_PATH / TLS_KEY_PATH: {}", e)),
+        ),
+        _ => None,
+    };
+
+    // The JWT key and certificate are loaded above, so by now the node can verify identities
+    state.ready.store(true, Ordering::Relaxed);
+
+    let addr = state.config.bind_addr;
+    // Peer addresses feed the per-IP connect limit
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Some(tls) = tls {
+        info!(%addr, "Koda Signal Node [ZRH] starting with TLS");
+        let handle = axum_server::Handle::new();
+        let stopper = handle.clone();
+        let drain = drain_on_signal(state.clone());
+        tokio::spawn(async move {
+            drain.await;
+            stopper.graceful_shutdown(Some(Duration::from_secs(5)));
+        });
+        axum_server::bind_rustls(addr, tls)
+            .handle(handle)
+            .serve(service)
+            .await
+            .unwrap_or_else(|e| panic!("Cannot serve TLS on BIND_ADDR {}: {}", addr, e));
+    } else {
+        info!(%addr, "Koda Signal Node [ZRH] starting");
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|e| panic!("Cannot bind BIND_ADDR {}: {}", addr, e));
+        axum::serve(listener, service)
+            .with_graceful_shutdown(drain_on_signal(state.clone()))
+            .await
+            .unwrap();
+    }
+
+    // Upgraded sockets outlive the HTTP server, so give them a moment to flush their Close
+    state.connections.close();
+    let _ = time::timeout(Duration::from_secs(5), state.connections.wait()).await;
+    info!("Koda Signal Node [ZRH] stopped");
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Warn every peer, let in-flight signaling finish for the grace period, then close all sockets
+async fn drain_on_signal(state: AppState) {
+    shutdown_signal().await;
+    state.ready.store(false, Ordering::Relaxed);
+
+    let drain_seconds = state.config.shutdown_grace.as_secs();
+    info!(peers = state.peers.len(), drain_seconds, "Shutdown requested, draining peers");
+    let spread_ms = state.config.reconnect_spread.as_millis() as u64;
+    for connections in state.peers.iter() {
+        for peer in connections.iter() {
+            peer.send(&KodaSignal::ServerShutdown {
+                drain_seconds,
+                reconnect_after_ms: jitter(spread_ms),
+                redirect_url: state.config.shutdown_redirect_url.clone(),
+            });
+        }
+    }
+
+    time::sleep(state.config.shutdown_grace).await;
+    state.shutdown.cancel();
+}
+
+// Uniform in 0..=max; v4 UUIDs are random enough to spread reconnects without another dependency
+fn jitter(max: u64) -> u64 {
+    (Uuid::new_v4().as_u128() % (max as u128 + 1)) as u64
+}
+
+// Browsers always send Origin; native clients usually don't and can't be used for CSRF
+fn origin_allowed(allowed: &[String], headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else { return true };
+    let Ok(origin) = origin.to_str() else { return false };
+    allowed.iter().any(|entry| entry == "*" || entry == origin)
+}
+
+// With our own TLS listener every socket is secure; otherwise only the proxy knows how the client came in
+fn upgrade_is_secure(config: &Config, headers: &HeaderMap) -> bool {
+    config.tls_cert_path.is_some()
+        || headers
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok())
+            // Chained proxies append; the first entry is what the client actually used
+            .and_then(|value| value.split(',').next())
+            .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> Response {
+    if state.draining.load(Ordering::Relaxed) {
+        counter!("koda_connections_rejected_total", "reason" => "draining").increment(1);
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    // Checked first: floods are rejected before any other work is done for them
+    let client_ip = connect_limit::client_ip(peer_addr, &headers, state.config.trust_forwarded_for);
+    if !state.connect_limiter.allow(client_ip) {
+        debug!(%client_ip, "Rejected WebSocket upgrade: connect rate exceeded");
+        counter!("koda_connections_rejected_total", "reason" => "ip_rate").increment(1);
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+
+    if !origin_allowed(&state.config.allowed_origins, &headers) {
+        warn!(origin = ?headers.get(header::ORIGIN), "Rejected WebSocket upgrade from disallowed origin");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    if state.config.require_secure && !upgrade_is_secure(&state.config, &headers) {
+        warn!(%client_ip, forwarded_proto = ?headers.get("x-forwarded-proto"), "Rejected plaintext WebSocket upgrade");
+        counter!("koda_connections_rejected_total", "reason" => "insecure").increment(1);
+        return (StatusCode::BAD_REQUEST, "REQUIRE_SECURE is set: connect with wss:// through a proxy that sends X-Forwarded-Proto: https")
+            .into_response();
+    }
+
+    // Clients that don't ask for a subprotocol predate versioning and speak v1
+    let ws = ws.protocols(ProtocolVersion::SUPPORTED.map(ProtocolVersion::name));
+    let protocol = match ws.selected_protocol() {
+        Some(selected) => selected.to_str().ok().and_then(ProtocolVersion::from_name),
+        None if !headers.contains_key(header::SEC_WEBSOCKET_PROTOCOL) => Some(ProtocolVersion::V1),
+        None => None,
+    };
+    let Some(protocol) = protocol else {
+        debug!(requested = ?headers.get(header::SEC_WEBSOCKET_PROTOCOL), "Rejected WebSocket upgrade: unsupported protocol version");
+        counter!("koda_connections_rejected_total", "reason" => "unsupported_protocol").increment(1);
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let Some(slot) = ConnectionSlot::acquire(&state.open_connections, state.config.max_connections) else {
+        warn!(max = state.config.max_connections, "Rejected WebSocket upgrade: node is at capacity");
+        counter!("koda_connections_rejected_total", "reason" => "node_full").increment(1);
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(MAX_USER_AGENT_LEN).collect::<String>());
+
+    let connections = state.connections.clone();
+    // Enforced by the WebSocket codec, before a giant frame is ever buffered in full or parsed
+    ws.max_message_size(state.config.max_message_bytes)
+        .max_frame_size(state.config.max_frame_bytes)
+        .on_upgrade(move |socket| {
+            let connection_id = Uuid::new_v4();
+            let info = ConnectionInfo {
+                client_ip,
+                user_agent,
+                connected_at: unix_millis(),
+                protocol,
+                traffic: Traffic::default(),
+            };
+            // user_id is filled in once the socket identifies
+            let span = tracing::info_span!(
+                "connection",
+                %connection_id,
+                client_ip = %info.client_ip,
+                user_agent = info.user_agent.as_deref().unwrap_or(""),
+                protocol = info.protocol.name(),
+                user_id = tracing::field::Empty,
+            );
+            connections.track_future(
+                async move {
+                    let _slot = slot;
+                    handle_socket(socket, state, connection_id, info).await
+                }
+                .instrument(span),
+            )
+        })
+        .into_response()
+}
+
+/// One of MAX_CONNECTIONS, held for as long as the socket (or its pending upgrade) lives.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn acquire(open: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        open.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max).then_some(n + 1))
+            .ok()?;
+        Some(ConnectionSlot(open.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState, connection_id: Uuid, info: ConnectionInfo) {
+    let (mut sender, mut receiver) = socket.split();
+    // Bounded so a stuck client can't make the node buffer without limit
+    let (tx, mut rx) = mpsc::channel(state.config.channel_capacity);
+    let mut session = Session { dedup: Dedup::new(state.config.signal_dedup_window), ..Session::default() };
+    let mut me = PeerConnection {
+        connection_id,
+        tx: Arc::new(tx.clone()),
+        framing: Framing::Text,
+        kicked: CancellationToken::new(),
+        info: Arc::new(info),
+    };
+    let mut framing_locked = false;
+
+    // Task 1: Forward messages from the channel to the WebSocket
+    let (ping_period, ping_jitter) = (state.config.ping_interval, state.config.ping_jitter);
+    let (send_timeout, flush_grace) = (state.config.send_timeout, state.config.send_flush_grace);
+    let info = me.info.clone();
+    let mut send_task = tokio::spawn(async move {
+        // A random first ping keeps sockets that connected together, e.g. after a mass reconnect, out of lockstep
+        let mut next_ping = Instant::now() + Duration::from_millis(jitter(ping_period.as_millis() as u64));
+        loop {
+            tokio::select! {
+                msg = rx.recv() => {
+                    // Every sender is gone and everything queued has been written
+                    let Some(msg) = msg else { break };
+                    // A queued Close is the last frame we ever send on this socket
+                    let closing = matches!(msg, Message::Close(_));
+                    let size = match &msg {
+                        Message::Text(text) => Some(text.len()),
+                        Message::Binary(bytes) => Some(bytes.len()),
+                        _ => None,
+                    };
+                    if !send_within(&mut sender, msg, send_timeout).await || closing { break; }
+                    if let Some(size) = size {
+                        info.traffic.sent(size);
+                    }
+                }
+                _ = time::sleep_until(next_ping) => {
+                    if !send_within(&mut sender, Message::Ping(vec![].into()), send_timeout).await { break; }
+                    let spread = Duration::from_millis(jitter(2 * ping_jitter.as_millis() as u64));
+                    next_ping = Instant::now() + ping_period - ping_jitter + spread;
+                }
+            }
+        }
+    });
+
+    // Task 2: Receive and Route messages, dropping the socket if pongs stop arriving
+    let mut last_pong = Instant::now();
+    // Pongs keep TCP alive but don't count as activity for the idle timeout
+    let mut last_app_message = Instant::now();
+    let mut idle_expired = false;
+    let mut liveness_check = time::interval(state.config.ping_interval);
+    // Unauthenticated sockets only get a short window to present a token
+    let identify_deadline = time::sleep(state.config.identify_timeout);
+    tokio::pin!(identify_deadline);
+    let mut identify_expired = false;
+    // Bounds how long one authentication lasts, however often REIDENTIFY extends the token
+    let lifetime = state.config.max_connection_lifetime;
+    let lifetime_deadline = time::sleep(lifetime);
+    tokio::pin!(lifetime_deadline);
+    let mut lifetime_expired = false;
+    let mut shutting_down = false;
+    // Per-connection so one noisy client can't starve the others
+    let mut rate_limiter = TokenBucket::new(state.config.rate_limit_per_sec, state.config.rate_limit_burst);
+    let mut rate_limited_streak = 0u32;
+    // A panic in one handler must only cost this connection, never skip the cleanup below
+    let served = AssertUnwindSafe(async {
+        loop {
+            tokio::select! {
+                frame = receiver.next() => {
+                    let msg = match frame {
+                        Some(Ok(msg)) => msg,
+                        // A reset or protocol violation; without this it would look like the socket just vanished
+                        Some(Err(e)) => {
+                            warn!(user_id = ?session.user_id, error = %e, "Connection failed");
+                            counter!("koda_connection_errors_total").increment(1);
+                            break;
+                        }
+                        None => {
+                            info!(user_id = ?session.user_id, "Connection ended without a close frame");
+                            break;
+                        }
+                    };
+                    // Any frame proves the client is still there, control frames included
+                    last_pong = Instant::now();
+                    let (framing, payload) = match &msg {
+                        Message::Text(text) => (Framing::Text, Ok(text.as_str())),
+                        Message::Binary(bytes) => (Framing::Binary, std::str::from_utf8(bytes)),
+                        Message::Close(frame) => {
+                            let (code, reason) = frame
+                                .as_ref()
+                                .map(|f| (f.code, f.reason.as_str()))
+                                .unwrap_or((close_code::NORMAL, ""));
+                            info!(code, reason, "Client closed connection");
+                            // Echo the close as a courtesy and give the send task a moment to flush it
+                            close(&tx, code, reason);
+                            let _ = time::timeout(flush_grace, &mut send_task).await;
+                            break;
+                        }
+                        // The WebSocket layer has already queued the matching Pong
+                        Message::Ping(data) => {
+                            trace!(bytes = data.len(), "Client ping");
+                            continue;
+                        }
+                        Message::Pong(_) => continue,
+                    };
+                    last_app_message = Instant::now();
+                    me.info.traffic.received(match &msg {
+                        Message::Binary(bytes) => bytes.len(),
+                        _ => payload.map_or(0, str::len),
+                    });
+                    if !framing_locked {
+                        me.framing = framing;
+                        framing_locked = true;
+                    }
+                    if !rate_limiter.try_acquire() {
+                        debug!(reason = "rate_limited", "Message dropped");
+                        counter!("koda_signals_dropped_total", "reason" => "rate_limited").increment(1);
+                        // A whole second burst while already limited is a client that isn't backing off
+                        rate_limited_streak += 1;
+                        if rate_limited_streak as f64 >= state.config.rate_limit_burst {
+                            info!(reason = "rate_limited", "Closing connection that ignores rate limiting");
+                            close_with_error(&me, ErrorCode::RateLimited);
+                            let _ = time::timeout(flush_grace, &mut send_task).await;
+                            break;
+                        }
+                        me.send(&KodaSignal::error(ErrorCode::RateLimited));
+                        if let Ok(text) = payload
+                            && let Some((target_id, msg_id)) = status_requested(text)
+                        {
+                            me.send(&KodaSignal::SignalStatus { target_id, status: DeliveryStatus::RateLimited, msg_id });
+                        }
+                        continue;
+                    }
+                    rate_limited_streak = 0;
+                    match payload {
+                        Ok(text) => {
+                            handle_text(text, &state, &me, &mut session).await
+                        }
+                        Err(_) => me.send(&KodaSignal::error(ErrorCode::MalformedJson)),
+                    }
+                }
+                _ = liveness_check.tick() => {
+                    if last_pong.elapsed() > state.config.pong_timeout {
+                        info!(reason = "pong_timeout", "Dropping unresponsive connection");
+                        break;
+                    }
+                    let idle_timeout = state.config.idle_timeout;
+                    if !idle_timeout.is_zero() && !idle_expired && last_app_message.elapsed() > idle_timeout {
+                        idle_expired = true;
+                        info!(reason = "idle_timeout", "Closing idle connection");
+                        close_with_error(&me, ErrorCode::IdleTimeout);
+                    }
+                }
+                _ = &mut identify_deadline, if session.user_id.is_none() && !identify_expired => {
+                    identify_expired = true;
+                    close_with_error(&me, ErrorCode::AuthTimeout);
+                }
+                _ = &mut lifetime_deadline, if !lifetime.is_zero() && !lifetime_expired => {
+                    lifetime_expired = true;
+                    // Resuming would skip the re-authentication this is meant to force
+                    if let Some(nonce) = session.resume_nonce.take() {
+                        state.resume.revoke(nonce);
+                    }
+                    info!(reason = "session_expired", "Closing connection at its maximum lifetime");
+                    close_with_error(&me, ErrorCode::SessionExpired);
+                }
+                // The future is built even when disabled, hence the fallback instant
+                _ = time::sleep_until(session.expires_at.unwrap_or_else(Instant::now)), if session.expires_at.is_some() => {
+                    session.expires_at = None;
+                    info!(reason = "token_expired", "Closing session with expired token");
+                    close_with_error(&me, ErrorCode::TokenExpired);
+                }
+                _ = time::sleep_until(session.sequencer.next_deadline().unwrap_or_else(Instant::now)),
+                    if session.sequencer.next_deadline().is_some() => {
+                    for (target_id, signal) in session.sequencer.expire(Instant::now()) {
+                        forward_signal(&state, &me, target_id, signal).await;
+                    }
+                }
+                _ = me.kicked.cancelled() => {
+                    info!(reason = "kicked", "Closing connection");
+                    let _ = time::timeout(flush_grace, &mut send_task).await;
+                    break;
+                }
+                _ = state.shutdown.cancelled(), if !shutting_down => {
+                    shutting_down = true;
+                    close(&tx, close_codes::SERVER_SHUTDOWN, "SERVER_SHUTDOWN");
+                }
+                // The send task exits once it has flushed a Close (or the socket died)
+                _ = &mut send_task => break,
+            }
+        }
+    })
+    .catch_unwind()
+    .await;
+    if let Err(panic) = served {
+        let reason = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown");
+        error!(reason, "Connection handler panicked");
+        counter!("koda_connection_panics_total").increment(1);
+    }
+
+    // Cleanup: Remove user when they disconnect
+    if let Some(nonce) = session.resume_nonce {
+        state.resume.release(nonce);
+    }
+    if let Some(uid) = session.user_id {
+        disconnect_peer(&state, uid, connection_id, None).await;
+        info!(user_id = %uid, "User disconnected from ZRH node");
+    }
+    // Closing our side of the queue lets the writer finish what is queued, usually the ERROR and
+    // Close saying why, instead of the client seeing a bare reset
+    drop(me);
+    drop(tx);
+    if !send_task.is_finished() && time::timeout(flush_grace, &mut send_task).await.is_err() {
+        send_task.abort();
+    }
+}
+
+/// Per-socket state the read loop threads through every message.
+#[derive(Default)]
+struct Session {
+    user_id: Option<Uuid>,
+    // When the current token lapses; pushed back by REIDENTIFY
+    expires_at: Option<Instant>,
+    // The outstanding resume grant for this socket, if any
+    resume_nonce: Option<Uuid>,
+    // Reorders this connection's `seq`-numbered signals before they are routed
+    sequencer: Sequencer<KodaSignal>,
+    // Drops retried copies of unsequenced signals
+    dedup: Dedup,
+    // Rejected IDENTIFY, REIDENTIFY and RESUME attempts on this socket
+    failed_auth_attempts: u32,
+}
+
+async fn handle_text(text: &str, state: &AppState, me: &PeerConnection, session: &mut Session) {
+    let parsed = match parse_signal(text) {
+        // Resolved up front so it then passes every check a SIGNAL would
+        Ok(KodaSignal::SignalByHandle { handle, data }) if session.user_id.is_some() => match resolve_handle(state, &handle).await {
+            Some(target_id) => Ok(KodaSignal::Signal {
+                target_id,
+                sender_id: None,
+                data,
+                msg_id: None,
+                seq: None,
+                server_ts: None,
+                want_status: None,
+                priority: None,
+            }),
+            None => {
+                debug!(reason = "unknown_handle", "Signal dropped");
+                counter!("koda_signals_dropped_total", "reason" => "unknown_handle").increment(1);
+                me.send(&KodaSignal::error(ErrorCode::UnknownHandle));
+                return;
+            }
+        },
+        parsed => parsed,
+    };
+    match parsed {
+        // One precondition for every message type, so none can slip through unauthenticated
+        Ok(signal) if signal.requires_identity() && session.user_id.is_none() => {
+            me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+        }
+        // The server stamps sender_id; a client setting it is either buggy or impersonating someone
+        Ok(signal) if signal.claims_sender() => {
+            warn!("Rejected message with a client-supplied sender_id");
+            counter!("koda_signals_dropped_total", "reason" => "sender_id_not_allowed").increment(1);
+            me.send(&KodaSignal::error(ErrorCode::SenderIdNotAllowed));
+        }
+        // The socket is already closing; don't spend another token decode on it
+        Ok(KodaSignal::Identify { .. } | KodaSignal::Reidentify { .. } | KodaSignal::Resume { .. })
+            if session.failed_auth_attempts >= state.config.max_auth_attempts => {}
+        Ok(signal) => match signal {
+            // STEP 1: Identification using the API's JWT
+            KodaSignal::Identify { token } => {
+                match state.jwt.verify(&token) {
+                    Ok(claims) => {
+                        info!(user_id = %claims.sub, "Identify succeeded");
+                        start_session(state, me, session, claims).await;
+                    }
+                    Err(e) => {
+                        let code = auth_error_code(&e);
+                        warn!(reason = ?code, error = %e, "Identify failed");
+                        if auth_failed(state, me, session) {
+                            return;
+                        }
+                        // The issuer's clock is ahead, not the token bad: the client may retry on this socket
+                        if code == ErrorCode::TokenNotYetValid {
+                            me.send(&token_error(state, &token, &e));
+                        } else {
+                            close_with_error(me, code);
+                        }
+                    }
+                }
+            },
+
+            // Reconnect shortcut: re-bind to a dropped session without the JWT
+            KodaSignal::Resume { resume_token } => {
+                match state.resume.redeem(&resume_token) {
+                    Some(resumed) => {
+                        info!(user_id = %resumed.claims.sub, "Session resumed");
+                        // The old socket is usually a ghost that hasn't hit its pong timeout yet
+                        if let Some(connections) = state.peers.get(&resumed.claims.sub) {
+                            for peer in connections.iter().filter(|peer| peer.connection_id == resumed.connection_id) {
+                                peer.kicked.cancel();
+                            }
+                        }
+                        start_session(state, me, session, resumed.claims).await;
+                    }
+                    // Not fatal: the client falls back to a full IDENTIFY on this socket
+                    None => {
+                        warn!("Resume rejected");
+                        if !auth_failed(state, me, session) {
+                            me.send(&KodaSignal::error(ErrorCode::ResumeFailed));
+                        }
+                    }
+                }
+            },
+
+            // Token rotation for long sessions: same user, fresh token, no reconnect
+            KodaSignal::Reidentify { token } => {
+                let Some(current) = session.user_id else {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    return;
+                };
+                match state.jwt.verify(&token) {
+                    Ok(claims) if claims.sub == current => {
+                        session.expires_at = Some(session_deadline(&claims, state.config.jwt_leeway));
+                        session.failed_auth_attempts = 0;
+                        debug!("Reidentify succeeded");
+                        me.send(&KodaSignal::Authenticated { user_id: current, connection_id: me.connection_id });
+                        issue_resume_token(state, me, session, &claims);
+                    }
+                    Ok(claims) => {
+                        warn!(token_sub = %claims.sub, "Reidentify rejected: token belongs to another user");
+                        if !auth_failed(state, me, session) {
+                            me.send(&KodaSignal::error(ErrorCode::IdentityMismatch));
+                        }
+                    }
+                    // The current session stays valid, so the client may retry with another token
+                    Err(e) => {
+                        let code = auth_error_code(&e);
+                        warn!(reason = ?code, error = %e, "Reidentify failed");
+                        if !auth_failed(state, me, session) {
+                            me.send(&token_error(state, &token, &e));
+                        }
+                    }
+                }
+            },
+
+            // STEP 2: Secure Routing
+            KodaSignal::Signal { target_id, data, msg_id, seq, want_status, priority, .. } => {
+                histogram!("koda_signal_payload_bytes").record(text.len() as f64);
+                match session.user_id {
+                    Some(sender_id) => {
+                        let refused = if !payload_fits(state, me, &data) || !signal_data_allowed(state, me, &data) {
+                            Some(DeliveryStatus::Rejected)
+                        } else {
+                            match may_route(state, me, sender_id, target_id).await {
+                                Ok(()) => None,
+                                Err(refused) => {
+                                    dead_letter(state, &refused, "SIGNAL", sender_id, target_id, Some(&data));
+                                    Some(refused.status(state))
+                                }
+                            }
+                        };
+                        // Caught before the quota so a repeat costs nothing
+                        let refused = refused.or_else(|| {
+                            let duplicate = match seq {
+                                Some(seq) => session.sequencer.is_repeat(target_id, seq),
+                                None => session.dedup.is_duplicate(target_id, &data, Instant::now()),
+                            };
+                            duplicate.then(|| {
+                                debug!(%target_id, ?seq, reason = "duplicate", "Signal dropped");
+                                counter!("koda_signals_dropped_total", "reason" => "duplicate").increment(1);
+                                DeliveryStatus::Rejected
+                            })
+                        });
+                        let refused = match refused {
+                            None if !within_quota(state, me, sender_id, 1).await => Some(DeliveryStatus::RateLimited),
+                            refused => refused,
+                        };
+                        if let Some(status) = refused {
+                            if let Some(msg_id) = msg_id {
+                                me.send(&KodaSignal::Ack { msg_id, delivered: false });
+                            }
+                            if want_status == Some(true) {
+                                me.send(&KodaSignal::SignalStatus { target_id, status, msg_id });
+                            }
+                            return;
+                        }
+                        // Only now, so a signal refused above isn't taken for a duplicate when retried
+                        if seq.is_none() {
+                            session.dedup.remember(target_id, &data, Instant::now());
+                        }
+                        let routed = KodaSignal::Signal {
+                            target_id,
+                            sender_id: Some(sender_id),
+                            data,
+                            msg_id,
+                            seq,
+                            server_ts: None,
+                            want_status,
+                            priority,
+                        };
+                        let Some(seq) = seq else {
+                            forward_signal(state, me, target_id, routed).await;
+                            return;
+                        };
+                        match session.sequencer.accept(target_id, seq, routed, Instant::now()) {
+                            Some(ready) => {
+                                for signal in ready {
+                                    forward_signal(state, me, target_id, signal).await;
+                                }
+                            }
+                            None => {
+                                debug!(%target_id, seq, reason = "duplicate", "Signal dropped");
+                                counter!("koda_signals_dropped_total", "reason" => "duplicate").increment(1);
+                                if want_status == Some(true) {
+                                    me.send(&KodaSignal::SignalStatus { target_id, status: DeliveryStatus::Rejected, msg_id });
+                                }
+                            }
+                        }
+                    },
+                    None => {
+                        // Send error if they try to signal without identifying
+                        me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    }
+                }
+            },
+
+            KodaSignal::MultiSignal { mut target_ids, data } => {
+                histogram!("koda_signal_payload_bytes").record(text.len() as f64);
+                let Some(sender_id) = session.user_id else {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    return;
+                };
+                if target_ids.len() > MAX_MULTI_SIGNAL_TARGETS {
+                    me.send(&KodaSignal::error(ErrorCode::LimitExceeded));
+                    return;
+                }
+                if !payload_fits(state, me, &data) || !signal_data_allowed(state, me, &data) {
+                    return;
+                }
+                let mut seen = HashSet::new();
+                target_ids.retain(|target_id| seen.insert(*target_id));
+                let mut admissions = Vec::with_capacity(target_ids.len());
+                for target_id in target_ids {
+                    admissions.push((target_id, check_route(state, sender_id, target_id).await));
+                }
+                // Only targets that will actually be routed to count against the quota
+                let routable = admissions.iter().filter(|(_, admitted)| admitted.is_ok()).count() as u64;
+                if routable > 0 && !within_quota(state, me, sender_id, routable).await {
+                    return;
+                }
+                let mut offline = Vec::new();
+                for (target_id, admitted) in admissions {
+                    let outcome = match admitted {
+                        Ok(()) => {
+                            let routed = KodaSignal::Signal {
+                                target_id,
+                                sender_id: Some(sender_id),
+                                data: data.clone(),
+                                msg_id: None,
+                                seq: None,
+                                server_ts: Some(unix_millis()),
+                                want_status: None,
+                                priority: None,
+                            };
+                            route_signal(state, target_id, routed).await
+                        }
+                        Err(refused) => {
+                            dead_letter(state, &refused, "MULTI_SIGNAL", sender_id, target_id, Some(&data));
+                            refused
+                        }
+                    };
+                    // No per-target replies: the summary covers them, and must not reveal blocks
+                    record_outcome(target_id, &outcome);
+                    if matches!(outcome, RoutingOutcome::PeerOffline(_) | RoutingOutcome::Queued) {
+                        state.presence.await_online(sender_id, target_id);
+                    }
+                    if !outcome.delivered() {
+                        offline.push(target_id);
+                    }
+                }
+                me.send(&KodaSignal::MultiSignalResult { offline });
+            },
+
+            // Call teardown travels the same path as Signal so state machines needn't infer it
+            KodaSignal::Hangup { target_id, reason, .. } => {
+                match session.user_id {
+                    Some(sender_id) => {
+                        let routed = KodaSignal::Hangup {
+                            target_id,
+                            sender_id: Some(sender_id),
+                            reason,
+                        };
+                        match may_route(state, me, sender_id, target_id).await {
+                            Ok(()) => {
+                                route_to_peer(state, me, target_id, routed).await;
+                            }
+                            Err(refused) => dead_letter(state, &refused, "HANGUP", sender_id, target_id, None),
+                        }
+                    },
+                    None => me.send(&KodaSignal::error(ErrorCode::IdentifyRequired)),
+                }
+            },
+            KodaSignal::CandidateBatch { target_id, candidates, .. } => {
+                histogram!("koda_signal_payload_bytes").record(text.len() as f64);
+                let Some(sender_id) = session.user_id else {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    return;
+                };
+                if candidates.len() > MAX_CANDIDATE_BATCH {
+                    me.send(&KodaSignal::error(ErrorCode::LimitExceeded));
+                    return;
+                }
+                if candidates.is_empty()
+                    || !payload_fits(state, me, &candidates)
+                    || !candidates.iter().all(|candidate| signal_data_allowed(state, me, candidate))
+                {
+                    return;
+                }
+                // One routed message, so it costs one signal however many candidates it carries
+                if !within_quota(state, me, sender_id, 1).await {
+                    return;
+                }
+                match may_route(state, me, sender_id, target_id).await {
+                    Ok(()) => {
+                        let routed = KodaSignal::CandidateBatch { target_id, sender_id: Some(sender_id), candidates };
+                        route_to_peer(state, me, target_id, routed).await;
+                    }
+                    Err(refused) => dead_letter(state, &refused, "CANDIDATE_BATCH", sender_id, target_id, None),
+                }
+            },
+            // UI events (typing, reactions) ride the routing path but are never queued, acked or reported
+            KodaSignal::Ephemeral { target_id, kind, data, .. } => {
+                let Some(sender_id) = session.user_id else {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    return;
+                };
+                if payload_fits(state, me, &data) && may_route(state, me, sender_id, target_id).await.is_ok() {
+                    let routed = KodaSignal::Ephemeral { target_id, sender_id: Some(sender_id), kind, data };
+                    if let Some(text) = to_json(&routed) {
+                        route_ephemeral(state, target_id, &text);
+                    }
+                }
+            },
+            KodaSignal::Subscribe { peer_ids } => {
+                match session.user_id {
+                    Some(uid) => {
+                        if !state.presence.subscribe(uid, &peer_ids, state.config.max_subscriptions_per_user) {
+                            me.send(&KodaSignal::error(ErrorCode::LimitExceeded));
+                        }
+                    }
+                    None => me.send(&KodaSignal::error(ErrorCode::IdentifyRequired)),
+                }
+            },
+            // Group calls: membership is per user, so every device of a member receives room traffic
+            KodaSignal::JoinRoom { room_id } => {
+                let Some(uid) = session.user_id else {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    return;
+                };
+                match state.rooms.join(room_id, uid, state.config.max_room_members, state.config.max_rooms_per_user) {
+                    Join::Joined(members) => {
+                        let joined = KodaSignal::PeerJoined { room_id, peer_id: uid };
+                        for &member in &members {
+                            send_to_user(&state.peers, member, &joined);
+                        }
+                        me.send(&KodaSignal::RoomMembers { room_id, members });
+                    }
+                    Join::AlreadyMember(members) => me.send(&KodaSignal::RoomMembers { room_id, members }),
+                    Join::Full => me.send(&KodaSignal::error(ErrorCode::RoomFull)),
+                    Join::TooManyRooms => me.send(&KodaSignal::error(ErrorCode::LimitExceeded)),
+                }
+            },
+            KodaSignal::LeaveRoom { room_id } => {
+                match session.user_id {
+                    Some(uid) => {
+                        if let Some(remaining) = state.rooms.leave(room_id, uid) {
+                            announce_departure(state, room_id, uid, &remaining);
+                        }
+                    }
+                    None => me.send(&KodaSignal::error(ErrorCode::IdentifyRequired)),
+                }
+            },
+            KodaSignal::RoomSignal { room_id, data, .. } => {
+                histogram!("koda_signal_payload_bytes").record(text.len() as f64);
+                let Some(sender_id) = session.user_id else {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    return;
+                };
+                if !state.rooms.is_member(room_id, sender_id) {
+                    me.send(&KodaSignal::error(ErrorCode::NotInRoom));
+                    return;
+                }
+                if !payload_fits(state, me, &data) || !signal_data_allowed(state, me, &data) {
+                    return;
+                }
+                if !within_quota(state, me, sender_id, 1).await {
+                    return;
+                }
+                let routed = KodaSignal::RoomSignal { room_id, sender_id: Some(sender_id), data };
+                let Some(text) = to_json(&routed) else { return };
+                for member in state.rooms.members_of(room_id) {
+                    if member != sender_id && !state.blocklist.is_blocked(member, sender_id) {
+                        deliver_local(&state.peers, member, &text);
+                    }
+                }
+            },
+            KodaSignal::Disconnect { reason } => {
+                // A resume token must not be able to undo a logout
+                if let Some(nonce) = session.resume_nonce.take() {
+                    state.resume.revoke(nonce);
+                }
+                // Taken so the socket's cleanup doesn't announce a second, reasonless departure
+                if let Some(uid) = session.user_id.take() {
+                    let reason: String = reason
+                        .filter(|reason| !reason.is_empty())
+                        .unwrap_or_else(|| "logout".to_owned())
+                        .chars()
+                        .take(MAX_DISCONNECT_REASON_LEN)
+                        .collect();
+                    info!(user_id = %uid, reason, "User logged out");
+                    disconnect_peer(state, uid, me.connection_id, Some(reason)).await;
+                }
+                // The send task stops after flushing this, which ends the connection
+                close(&*me.tx, close_code::NORMAL, "DISCONNECT");
+            },
+            KodaSignal::Block { peer_id } => {
+                match session.user_id {
+                    Some(uid) => state.blocklist.block(uid, peer_id),
+                    None => me.send(&KodaSignal::error(ErrorCode::IdentifyRequired)),
+                }
+            },
+            KodaSignal::Unblock { peer_id } => {
+                match session.user_id {
+                    Some(uid) => state.blocklist.unblock(uid, peer_id),
+                    None => me.send(&KodaSignal::error(ErrorCode::IdentifyRequired)),
+                }
+            },
+            KodaSignal::SetStatus { status } => {
+                let Some(uid) = session.user_id else {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                    return;
+                };
+                if status.chars().count() > MAX_STATUS_TEXT_LEN {
+                    me.send(&KodaSignal::error(ErrorCode::LimitExceeded));
+                    return;
+                }
+                state.presence.set_status_text(uid, status);
+                broadcast_presence(state, uid, PresenceStatus::Online, None);
+            },
+            KodaSignal::WhoIsOnline { peer_ids } => {
+                if session.user_id.is_none() {
+                    me.send(&KodaSignal::error(ErrorCode::IdentifyRequired));
+                } else if peer_ids.len() > MAX_PRESENCE_QUERY {
+                    me.send(&KodaSignal::error(ErrorCode::LimitExceeded));
+                } else {
+                    let (online, offline): (Vec<Uuid>, Vec<Uuid>) = peer_ids
+                        .into_iter()
+                        .partition(|peer_id| state.peers.contains_key(peer_id));
+                    let last_seen = offline
+                        .iter()
+                        .filter_map(|&peer_id| state.presence.last_seen(peer_id).map(|seen| (peer_id, seen)))
+                        .collect();
+                    let status_text = online
+                        .iter()
+                        .filter_map(|&peer_id| state.presence.status_text(peer_id).map(|text| (peer_id, text)))
+                        .collect();
+                    me.send(&KodaSignal::OnlineStatus { online, offline, last_seen, status_text });
+                }
+            },
+            // Liveness is already refreshed by the read loop; this just proves the path works end to end
+            KodaSignal::Heartbeat => me.send(&KodaSignal::HeartbeatAck { server_ts: unix_millis() }),
+            KodaSignal::Capabilities => me.send(&capabilities(state, me)),
+            // Server-to-client messages have no meaning when a client sends them
+            _ => me.send(&KodaSignal::Error {
+                code: ErrorCode::UnknownMessageType,
+                message: Some("message type is only sent by the server".to_owned()),
+                reset_at: None,
+                retry_after: None,
+            }),
+        },
+        Err(error) => me.send(&error),
+    }
+}
+
+fn capabilities(state: &AppState, me: &PeerConnection) -> KodaSignal {
+    let config = &state.config;
+    KodaSignal::CapabilitiesResult {
+        protocol_version: me.info.protocol,
+        supported_types: CLIENT_MESSAGE_TYPES.map(str::to_owned).to_vec(),
+        limits: Limits {
+            max_payload_bytes: config.max_payload_bytes,
+            max_message_bytes: config.max_message_bytes,
+            rate_limit_per_sec: config.rate_limit_per_sec,
+            rate_limit_burst: config.rate_limit_burst,
+            max_multi_signal_targets: MAX_MULTI_SIGNAL_TARGETS,
+            max_candidate_batch: MAX_CANDIDATE_BATCH,
+            max_presence_query: MAX_PRESENCE_QUERY,
+            max_subscriptions_per_user: config.max_subscriptions_per_user,
+            max_room_members: config.max_room_members,
+            max_rooms_per_user: config.max_rooms_per_user,
+            max_status_text_len: MAX_STATUS_TEXT_LEN,
+            daily_signal_quota: config.daily_signal_quota,
+        },
+    }
+}
+
+/// Counts a rejected token. Returns true if that was one too many and the socket is now closing,
+/// in which case the caller must not send its own error.
+fn auth_failed(state: &AppState, me: &PeerConnection, session: &mut Session) -> bool {
+    counter!("koda_auth_failures_total").increment(1);
+    session.failed_auth_attempts += 1;
+    if session.failed_auth_attempts < state.config.max_auth_attempts {
+        return false;
+    }
+    warn!(attempts = session.failed_auth_attempts, "Closing connection after too many failed auth attempts");
+    close_with_error(me, ErrorCode::TooManyAuthAttempts);
+    true
+}
+
+// Only parsed for frames the rate limiter dropped, which are otherwise never looked at
+fn status_requested(text: &str) -> Option<(Uuid, Option<Uuid>)> {
+    match serde_json::from_str(text).ok()? {
+        KodaSignal::Signal { target_id, msg_id, want_status: Some(true), .. } => Some((target_id, msg_id)),
+        _ => None,
+    }
+}
+
+/// Binds the socket to `claims.sub`, from a fresh IDENTIFY or a RESUME.
+async fn start_session(state: &AppState, me: &PeerConnection, session: &mut Session, claims: Claims) {
+    let uid = claims.sub;
+    // Checked before registering; a simultaneous identify may overshoot by one
+    let devices = state.peers.get(&uid).map_or(0, |connections| {
+        connections.iter().filter(|peer| peer.connection_id != me.connection_id && !peer.kicked.is_cancelled()).count()
+    });
+    if devices >= state.config.max_connections_per_user {
+        warn!(user_id = %uid, devices, "Session rejected: too many connections");
+        counter!("koda_connections_rejected_total", "reason" => "user_limit").increment(1);
+        close_with_error(me, ErrorCode::TooManyConnections);
+        return;
+    }
+    session.expires_at = Some(session_deadline(&claims, state.config.jwt_leeway));
+    session.failed_auth_attempts = 0;
+    tracing::Span::current().record("user_id", tracing::field::display(uid));
+    match session.user_id.replace(uid) {
+        // A token refresh: the socket is already registered, so only the grants change
+        Some(previous) if previous == uid => {
+            me.send(&KodaSignal::Authenticated { user_id: uid, connection_id: me.connection_id });
+            issue_resume_token(state, me, session, &claims);
+            return;
+        }
+        // Re-identifying as someone else must not leave a stale registration behind
+        Some(previous) => disconnect_peer(state, previous, me.connection_id, None).await,
+        None => {}
+    }
+    me.send(&KodaSignal::Authenticated { user_id: uid, connection_id: me.connection_id });
+    if !state.ice.is_empty() {
+        me.send(&KodaSignal::IceServers { servers: state.ice.servers_for(uid) });
+    }
+    issue_resume_token(state, me, session, &claims);
+    connect_peer(state, uid, me.clone()).await;
+}
+
+// Replaces any earlier grant so each socket has at most one live resume token
+fn issue_resume_token(state: &AppState, me: &PeerConnection, session: &mut Session, claims: &Claims) {
+    if !state.resume.is_enabled() {
+        return;
+    }
+    let (nonce, resume_token) = state.resume.issue(claims, me.connection_id);
+    if let Some(previous) = session.resume_nonce.replace(nonce) {
+        state.resume.revoke(previous);
+    }
+    me.send(&KodaSignal::ResumeToken { resume_token });
+}
+
+// A frame that can't be encoded is logged and skipped instead of panicking the connection's task
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Option<String> {
+    serde_json::to_string(value)
+        .inspect_err(|e| {
+            error!(error = %e, "Failed to serialize outbound frame");
+            counter!("koda_serialization_errors_total").increment(1);
+        })
+        .ok()
+}
+
+/// Separates text that isn't JSON at all from JSON that isn't a message we understand.
+fn parse_signal(text: &str) -> Result<KodaSignal, Box<KodaSignal>> {
+    let value: serde_json::Value =
+        serde_json::from_str(text).map_err(|_| KodaSignal::error(ErrorCode::MalformedJson))?;
+    let message_type = value.get("type").and_then(|t| t.as_str()).map(str::to_owned);
+    serde_json::from_value(value).map_err(|e| {
+        Box::new(KodaSignal::Error {
+            code: ErrorCode::UnknownMessageType,
+            message: Some(match message_type {
+                Some(message_type) => format!("{}: {}", message_type, e),
+                None => e.to_string(),
+            }),
+            reset_at: None,
+            retry_after: None,
+        })
+    })
+}
+
+// A token accepted within the leeway would otherwise be closed the moment it identifies
+fn session_deadline(claims: &Claims, leeway: Duration) -> Instant {
+    Instant::now() + claims.expires_in(leeway).saturating_sub(SESSION_EXPIRY_SKEW)
+}
+
+// Tell the client why so it can refresh instead of retrying blindly
+fn auth_error_code(e: &JwtError) -> ErrorCode {
+    match e.kind() {
+        JwtErrorKind::ExpiredSignature => ErrorCode::TokenExpired,
+        JwtErrorKind::InvalidToken => ErrorCode::InvalidToken,
+        JwtErrorKind::ImmatureSignature => ErrorCode::TokenNotYetValid,
+        _ => ErrorCode::Unauthorized,
+    }
+}
+
+// A future-dated token comes with how long to wait, rounded up so retrying then succeeds
+fn token_error(state: &AppState, token: &str, e: &JwtError) -> KodaSignal {
+    let code = auth_error_code(e);
+    let retry_after = match code {
+        ErrorCode::TokenNotYetValid => state
+            .jwt
+            .usable_in(token, state.config.jwt_leeway)
+            .map(|wait| (wait.as_secs_f64().ceil() as u64).max(1)),
+        _ => None,
+    };
+    KodaSignal::Error { code, message: None, reset_at: None, retry_after }
+}
+
+/// Routes a signal that passed every check and acknowledges it if the sender asked.
+async fn forward_signal(state: &AppState, me: &PeerConnection, target_id: Uuid, mut routed: KodaSignal) {
+    let (msg_id, sender_id, want_status) = match &mut routed {
+        KodaSignal::Signal { msg_id, sender_id, server_ts, want_status, .. } => {
+            // Stamped as late as possible so the target sees when the node actually forwarded it
+            *server_ts = Some(unix_millis());
+            (*msg_id, *sender_id, want_status.take() == Some(true))
+        }
+        _ => (None, None, false),
+    };
+    let outcome = route_signal(state, target_id, routed).await;
+    report_outcome(state, me, target_id, &outcome);
+    // The sender gets PEER_ONLINE when the target is back, so it can retry instead of polling
+    if matches!(outcome, RoutingOutcome::PeerOffline(_) | RoutingOutcome::Queued)
+        && let Some(sender_id) = sender_id
+    {
+        state.presence.await_online(sender_id, target_id);
+    }
+    if let Some(msg_id) = msg_id {
+        me.send(&KodaSignal::Ack { msg_id, delivered: outcome.delivered() });
+    }
+    if want_status {
+        me.send(&KodaSignal::SignalStatus { target_id, status: outcome.status(state), msg_id });
+    }
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+// Checked after parsing since `data` is arbitrary JSON; SDP/ICE are far below the limit
+fn payload_fits(state: &AppState, me: &PeerConnection, data: &impl Serialize) -> bool {
+    let size = serde_json::to_vec(data).map(|bytes| bytes.len()).unwrap_or(usize::MAX);
+    if size <= state.config.max_payload_bytes {
+        return true;
+    }
+    debug!(size, reason = "payload_too_large", "Signal dropped");
+    counter!("koda_signals_dropped_total", "reason" => "payload_too_large").increment(1);
+    me.send(&KodaSignal::error(ErrorCode::PayloadTooLarge));
+    false
+}
+
+// Only SIGNAL, CANDIDATE_BATCH, MULTI_SIGNAL targets and ROOM_SIGNAL count; hangups, presence and heartbeats never do
+async fn within_quota(state: &AppState, me: &PeerConnection, sender_id: Uuid, count: u64) -> bool {
+    let Err(reset_at) = state.quota.consume(sender_id, count).await else { return true };
+    debug!(user_id = %sender_id, reason = "quota_exceeded", "Signal dropped");
+    counter!("koda_signals_dropped_total", "reason" => "quota_exceeded").increment(count);
+    me.send(&KodaSignal::Error { code: ErrorCode::QuotaExceeded, message: None, reset_at: Some(reset_at), retry_after: None });
+    false
+}
+
+// With SIGNAL_DATA_KEYS set, only objects carrying one of those keys are routed as signals
+fn signal_data_allowed(state: &AppState, me: &PeerConnection, data: &serde_json::Value) -> bool {
+    // Nothing a peer could act on, so not worth a frame
+    let empty = match data {
+        serde_json::Value::Null => true,
+        serde_json::Value::String(text) => text.is_empty(),
+        serde_json::Value::Object(object) => object.is_empty(),
+        _ => false,
+    };
+    if empty {
+        debug!(reason = "empty_signal_data", "Signal dropped");
+        counter!("koda_signals_dropped_total", "reason" => "empty_signal_data").increment(1);
+        me.send(&KodaSignal::error(ErrorCode::EmptySignalData));
+        return false;
+    }
+    let keys = &state.config.signal_data_keys;
+    if keys.is_empty() || data.as_object().is_some_and(|object| keys.iter().any(|key| object.contains_key(key))) {
+        return true;
+    }
+    debug!(reason = "invalid_signal_data", "Signal dropped");
+    counter!("koda_signals_dropped_total", "reason" => "invalid_signal_data").increment(1);
+    me.send(&KodaSignal::error(ErrorCode::InvalidSignalData));
+    false
+}
+
+/// What became of a routed message. Decided without writing to the sender's socket;
+/// `report_outcome` turns it into replies and metrics.
+#[derive(Debug, PartialEq, Eq)]
+enum RoutingOutcome {
+    // Routing to yourself would just echo back to your own devices, a feedback loop waiting to happen
+    SelfTarget,
+    Blocked,
+    NotFriends,
+    Delivered,
+    Relayed,
+    Queued,
+    PeerBusy,
+    PeerOffline(Uuid),
+    // Couldn't be encoded; already logged by `to_json`
+    Unserializable,
+}
+
+impl RoutingOutcome {
+    /// True only if a live socket (or the node holding one) accepted the message.
+    fn delivered(&self) -> bool {
+        matches!(self, RoutingOutcome::Delivered | RoutingOutcome::Relayed)
+    }
+
+    /// What the sender is told, if anything.
+    fn reply(&self, state: &AppState) -> Option<KodaSignal> {
+        match self {
+            RoutingOutcome::SelfTarget => Some(KodaSignal::error(ErrorCode::SelfTarget)),
+            // Silent by default so senders can't probe who has blocked them
+            RoutingOutcome::Blocked => state.config.reveal_blocks.then(|| KodaSignal::error(ErrorCode::Blocked)),
+            RoutingOutcome::NotFriends => Some(KodaSignal::error(ErrorCode::NotFriends)),
+            RoutingOutcome::PeerBusy => Some(KodaSignal::error(ErrorCode::PeerBusy)),
+            // Let the sender know their friend is offline (and can't be queued for)
+            &RoutingOutcome::PeerOffline(peer_id) => Some(KodaSignal::PeerOffline {
+                peer_id,
+                last_seen: state.presence.last_seen(peer_id),
+            }),
+            RoutingOutcome::Delivered | RoutingOutcome::Relayed | RoutingOutcome::Queued => None,
+            RoutingOutcome::Unserializable => None,
+        }
+    }
+
+    /// The sender's view for SIGNAL_STATUS; hidden blocks look like any other refusal.
+    fn status(&self, state: &AppState) -> DeliveryStatus {
+        match self {
+            RoutingOutcome::Delivered | RoutingOutcome::Relayed => DeliveryStatus::Delivered,
+            RoutingOutcome::Queued => DeliveryStatus::Queued,
+            RoutingOutcome::PeerOffline(_) => DeliveryStatus::Offline,
+            RoutingOutcome::PeerBusy => DeliveryStatus::Busy,
+            RoutingOutcome::Blocked if state.config.reveal_blocks => DeliveryStatus::Blocked,
+            RoutingOutcome::Blocked
+            | RoutingOutcome::SelfTarget
+            | RoutingOutcome::NotFriends
+            | RoutingOutcome::Unserializable => DeliveryStatus::Rejected,
+        }
+    }
+
+    fn dropped_reason(&self) -> Option<&'static str> {
+        match self {
+            RoutingOutcome::SelfTarget => Some("self_target"),
+            RoutingOutcome::Blocked => Some("blocked"),
+            RoutingOutcome::NotFriends => Some("not_friends"),
+            RoutingOutcome::PeerBusy => Some("peer_busy"),
+            RoutingOutcome::PeerOffline(_) => Some("peer_offline"),
+            RoutingOutcome::Unserializable => Some("unserializable"),
+            RoutingOutcome::Delivered | RoutingOutcome::Relayed | RoutingOutcome::Queued => None,
+        }
+    }
+}
+
+// Blocked peers, and strangers when the friendship check is enabled, can't reach each other
+async fn check_route(state: &AppState, sender_id: Uuid, target_id: Uuid) -> Result<(), RoutingOutcome> {
+    if sender_id == target_id {
+        return Err(RoutingOutcome::SelfTarget);
+    }
+    if state.blocklist.is_blocked(target_id, sender_id) {
+        return Err(RoutingOutcome::Blocked);
+    }
+    if let Some(friendships) = &state.friendships
+        && !friendships.are_friends(sender_id, target_id).await
+    {
+        return Err(RoutingOutcome::NotFriends);
+    }
+    Ok(())
+}
+
+async fn resolve_handle(state: &AppState, handle: &str) -> Option<Uuid> {
+    state.handles.as_ref()?.resolve(handle).await
+}
+
+/// `check_route`, telling the sender about a refusal.
+async fn may_route(state: &AppState, me: &PeerConnection, sender_id: Uuid, target_id: Uuid) -> Result<(), RoutingOutcome> {
+    let admitted = check_route(state, sender_id, target_id).await;
+    if let Err(refused) = &admitted {
+        report_outcome(state, me, target_id, refused);
+    }
+    admitted
+}
+
+// Admission refusals happen before the routed message is built, hence the loose parts
+fn dead_letter(
+    state: &AppState,
+    outcome: &RoutingOutcome,
+    kind: &str,
+    sender_id: Uuid,
+    target_id: Uuid,
+    data: Option<&serde_json::Value>,
+) {
+    if let Some(letters) = &state.dead_letters
+        && let Some(reason) = outcome.dropped_reason()
+    {
+        letters.record(reason, kind, sender_id, target_id, data);
+    }
+}
+
+fn dead_letter_routed(state: &AppState, outcome: &RoutingOutcome, routed: &KodaSignal) {
+    if let Some(letters) = &state.dead_letters
+        && let Some(reason) = outcome.dropped_reason()
+    {
+        letters.record_routed(reason, routed);
+    }
+}
+
+/// Delivers an already sender-stamped message to every device of `target_id`,
+/// relaying it to another node or queueing it if there is none here.
+async fn route_signal(state: &AppState, target_id: Uuid, routed: KodaSignal) -> RoutingOutcome {
+    let Some(routed_msg) = to_json(&routed) else {
+        dead_letter_routed(state, &RoutingOutcome::Unserializable, &routed);
+        return RoutingOutcome::Unserializable;
+    };
+    let priority = routed.priority();
+    match deliver_local_with(&state.peers, target_id, &routed_msg, priority) {
+        Some(Delivery::Delivered) => return RoutingOutcome::Delivered,
+        Some(Delivery::Busy) => {
+            counter!("koda_signals_dropped_by_priority_total", "priority" => priority.as_str()).increment(1);
+            dead_letter_routed(state, &RoutingOutcome::PeerBusy, &routed);
+            return RoutingOutcome::PeerBusy;
+        }
+        // Every device hung up between the lookup and the send: the target is offline in all but name
+        Some(Delivery::Closed) => prune_closed(state, target_id).await,
+        None => {}
+    }
+    if relay_remote(state, target_id, &routed_msg).await {
+        RoutingOutcome::Relayed
+    } else {
+        match state.offline_queue.push(target_id, routed).await {
+            Ok(()) => RoutingOutcome::Queued,
+            Err(routed) => {
+                let outcome = RoutingOutcome::PeerOffline(target_id);
+                dead_letter_routed(state, &outcome, &routed);
+                outcome
+            }
+        }
+    }
+}
+
+// Runs the usual disconnect now instead of whenever the dying sockets get to it; theirs is then a no-op
+async fn prune_closed(state: &AppState, uid: Uuid) {
+    let closed: Vec<Uuid> = state.peers.get(&uid).map_or_else(Vec::new, |connections| {
+        connections.iter().filter(|peer| peer.tx.is_closed()).map(|peer| peer.connection_id).collect()
+    });
+    for connection_id in closed {
+        debug!(user_id = %uid, %connection_id, "Pruning connection that closed mid-route");
+        disconnect_peer(state, uid, connection_id, None).await;
+    }
+}
+
+fn report_outcome(state: &AppState, me: &PeerConnection, target_id: Uuid, outcome: &RoutingOutcome) {
+    record_outcome(target_id, outcome);
+    if let Some(reply) = outcome.reply(state) {
+        me.send(&reply);
+    }
+}
+
+// Logs and metrics only; the sender isn't told
+fn record_outcome(target_id: Uuid, outcome: &RoutingOutcome) {
+    match outcome {
+        RoutingOutcome::Delivered => {
+            debug!(%target_id, "Signal routed");
+            counter!("koda_signals_routed_total").increment(1);
+        }
+        RoutingOutcome::Relayed => {
+            debug!(%target_id, "Signal relayed to another node");
+            counter!("koda_signals_relayed_total").increment(1);
+        }
+        RoutingOutcome::Queued => debug!(%target_id, "Signal queued for offline peer"),
+        _ => {}
+    }
+    if let Some(reason) = outcome.dropped_reason() {
+        debug!(%target_id, reason, "Signal dropped");
+        counter!("koda_signals_dropped_total", "reason" => reason).increment(1);
+    }
+}
+
+/// Routes a message and tells the sender about PEER_OFFLINE / PEER_BUSY.
+/// Returns true only if it was delivered.
+async fn route_to_peer(state: &AppState, me: &PeerConnection, target_id: Uuid, routed: KodaSignal) -> bool {
+    let outcome = route_signal(state, target_id, routed).await;
+    report_outcome(state, me, target_id, &outcome);
+    outcome.delivered()
+}
+
+// Best effort only: offline, busy or unreachable targets simply miss it
+fn route_ephemeral(state: &AppState, target_id: Uuid, text: &str) {
+    if deliver_local(&state.peers, target_id, text).is_none() {
+        // Nobody waits for the outcome, so a slow sibling mustn't hold up the sender's socket
+        let (state, text) = (state.clone(), text.to_owned());
+        tokio::spawn(async move { relay_remote(&state, target_id, &text).await });
+    }
+}
+
+// Redis first, since it knows where the target is; the HTTP siblings are asked only if that fails
+async fn relay_remote(state: &AppState, target_id: Uuid, text: &str) -> bool {
+    if let Some(cluster) = &state.cluster
+        && cluster.relay(target_id, text).await
+    {
+        return true;
+    }
+    match &state.relay {
+        Some(relay) => relay.relay(target_id, text).await,
+        None => false,
+    }
+}
+
+enum Delivery {
+    Delivered,
+    Busy,
+    Closed,
+}
+
+/// Fans a frame out to every local device of `uid`; None if the user isn't on this node.
+///
+/// Backpressure policy: never block or evict. If no device has room the frame is dropped
+/// and reported as Busy so the sender can be told PEER_BUSY.
+fn deliver_local(peers: &PeerMap, uid: Uuid, text: &str) -> Option<Delivery> {
+    deliver_local_with(peers, uid, text, Priority::Normal)
+}
+
+fn deliver_local_with(peers: &PeerMap, uid: Uuid, text: &str, priority: Priority) -> Option<Delivery> {
+    let connections = peers.get(&uid)?;
+    let mut delivered = false;
+    let mut busy = false;
+    for peer in connections.iter() {
+        if !peer.has_room_for(priority) {
+            busy = true;
+            continue;
+        }
+        match peer.send_text(text) {
+            Ok(()) => delivered = true,
+            Err(TrySendError::Full(_)) => busy = true,
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+    Some(if delivered {
+        Delivery::Delivered
+    } else if busy {
+        Delivery::Busy
+    } else {
+        Delivery::Closed
+    })
+}
+
+/// False if the socket failed or didn't accept the frame within `deadline`; either way it is dead.
+async fn send_within(sender: &mut SplitSink<WebSocket, Message>, msg: Message, deadline: Duration) -> bool {
+    match time::timeout(deadline, sender.send(msg)).await {
+        Ok(sent) => sent.is_ok(),
+        Err(_) => {
+            warn!(timeout_secs = deadline.as_secs(), "Socket write timed out, dropping connection");
+            counter!("koda_send_timeouts_total").increment(1);
+            false
+        }
+    }
+}
+
+/// Tells the client why it is being dropped, then queues a Close so the send task winds down.
+fn close_with_error(me: &PeerConnection, code: ErrorCode) {
+    me.send(&KodaSignal::error(code));
+    close(&*me.tx, code.close_code(), to_json(&code).unwrap_or_default().trim_matches('"'));
+}
+
+fn close(tx: &dyn PeerSink, code: u16, reason: &str) {
+    let _ = tx.try_send(Message::Close(Some(CloseFrame { code, reason: reason.into() })));
+}
+
+async fn connect_peer(state: &AppState, uid: Uuid, peer: PeerConnection) {
+    // Queued signals go out before live routing resumes so their order is preserved
+    flush_offline_queue(state, uid, &peer).await;
+    let me = peer.clone();
+    let first_device = register_peer(&state.peers, uid, peer);
+    report_connection(state, uid, ConnectionEvent::Connected);
+    if first_device {
+        state.online_users.fetch_add(1, Ordering::Relaxed);
+        gauge!("koda_connected_peers").increment(1.0);
+        // Back within the grace period: subscribers were never told they'd gone
+        if !state.presence.end_grace(uid) {
+            broadcast_presence(state, uid, PresenceStatus::Online, None);
+        }
+        let online = KodaSignal::PeerOnline { peer_id: uid };
+        for sender in state.presence.take_awaiting(uid) {
+            send_to_user(&state.peers, sender, &online);
+        }
+        if let Some(cluster) = &state.cluster {
+            cluster.claim(uid).await;
+        }
+    }
+    // Catch anything queued between the first flush and registration
+    flush_offline_queue(state, uid, &me).await;
+}
+
+async fn flush_offline_queue(state: &AppState, uid: Uuid, peer: &PeerConnection) {
+    for signal in state.offline_queue.drain(uid).await {
+        peer.send(&signal);
+    }
+}
+
+/// `reason` is set when the user logged out rather than losing the connection.
+async fn disconnect_peer(state: &AppState, uid: Uuid, connection_id: Uuid, reason: Option<String>) {
+    // Already gone when pruned mid-route; the socket's own exit must not report it twice
+    let Some(last_device) = unregister_peer(&state.peers, uid, connection_id) else { return };
+    report_connection(state, uid, ConnectionEvent::Disconnected);
+    if last_device {
+        state.online_users.fetch_sub(1, Ordering::Relaxed);
+        gauge!("koda_connected_peers").decrement(1.0);
+        state.presence.record_last_seen(uid);
+        let grace = state.config.presence_grace;
+        if reason.is_none() && !grace.is_zero() {
+            // Mobile clients drop and reconnect on every network change; only treat them as gone if they stay gone
+            let token = state.presence.begin_grace(uid);
+            let state = state.clone();
+            tokio::spawn(async move {
+                time::sleep(grace).await;
+                if state.presence.grace_expired(uid, token) {
+                    broadcast_presence(&state, uid, PresenceStatus::Offline, None);
+                    forget_user(&state, uid).await;
+                }
+            });
+        } else {
+            // A logout is deliberate, so there's nothing to wait for
+            state.presence.end_grace(uid);
+            broadcast_presence(state, uid, PresenceStatus::Offline, reason);
+            forget_user(state, uid).await;
+        }
+    }
+}
+
+// Drops what the user set up while connected: subscriptions, status text, blocks and rooms
+async fn forget_user(state: &AppState, uid: Uuid) {
+    state.presence.unsubscribe_all(uid);
+    state.blocklist.clear(uid);
+    for (room_id, remaining) in state.rooms.leave_all(uid) {
+        announce_departure(state, room_id, uid, &remaining);
+    }
+    if let Some(cluster) = &state.cluster {
+        cluster.release(uid).await;
+    }
+}
+
+fn report_connection(state: &AppState, uid: Uuid, event: ConnectionEvent) {
+    if let Some(events) = &state.events {
+        let devices = state.peers.get(&uid).map_or(0, |connections| connections.len());
+        events.record(uid, event, devices);
+    }
+}
+
+// Queues are bounded, so this finds the one stuck client before it degrades everyone it talks to
+fn check_outbound_queues(state: &AppState) {
+    let mut max_depth = 0;
+    for connections in state.peers.iter() {
+        for peer in connections.iter().filter(|peer| !peer.kicked.is_cancelled()) {
+            let depth = peer.queue_depth();
+            max_depth = max_depth.max(depth);
+            if depth < state.config.slow_consumer_high_water {
+                continue;
+            }
+            warn!(user_id = %connections.key(), connection_id = %peer.connection_id, depth, "Slow consumer");
+            if state.config.slow_consumer_disconnect {
+                counter!("koda_slow_consumers_disconnected_total").increment(1);
+                peer.kick(ErrorCode::SlowConsumer);
+            }
+        }
+    }
+    gauge!("koda_outbound_queue_depth_max").set(max_depth as f64);
+}
+
+// Only subscribers that are currently connected can receive the update
+fn broadcast_presence(state: &AppState, uid: Uuid, status: PresenceStatus, reason: Option<String>) {
+    let status_text = match status {
+        PresenceStatus::Online => state.presence.status_text(uid),
+        PresenceStatus::Offline => None,
+    };
+    let update = KodaSignal::PresenceUpdate { user_id: uid, status, status_text, reason };
+    let subscribers = state.presence.subscribers_of(uid);
+    let Some(text) = to_json(&update).filter(|_| !subscribers.is_empty()) else { return };
+    histogram!("koda_presence_fanout_subscribers").record(subscribers.len() as f64);
+    let started = Instant::now();
+    if subscribers.len() <= PRESENCE_FANOUT_CHUNK {
+        for &subscriber in &subscribers {
+            send_text_to_user(&state.peers, subscriber, &text);
+        }
+        histogram!("koda_presence_fanout_seconds").record(started.elapsed().as_secs_f64());
+        return;
+    }
+    // Too many to loop over on the caller's task, which is usually the identifying socket's own
+    let peers = state.peers.clone();
+    tokio::spawn(async move {
+        for (i, chunk) in subscribers.chunks(PRESENCE_FANOUT_CHUNK).enumerate() {
+            if i > 0 {
+                time::sleep(PRESENCE_FANOUT_PAUSE).await;
+            }
+            for &subscriber in chunk {
+                send_text_to_user(&peers, subscriber, &text);
+            }
+        }
+        histogram!("koda_presence_fanout_seconds").record(started.elapsed().as_secs_f64());
+    });
+}
+
+fn announce_departure(state: &AppState, room_id: Uuid, uid: Uuid, remaining: &[Uuid]) {
+    let left = KodaSignal::PeerLeft { room_id, peer_id: uid };
+    for &member in remaining {
+        send_to_user(&state.peers, member, &left);
+    }
+}
+
+/// Best-effort delivery to every device of `uid`; offline users are skipped.
+fn send_to_user(peers: &PeerMap, uid: Uuid, signal: &KodaSignal) {
+    if peers.contains_key(&uid)
+        && let Some(text) = to_json(signal)
+    {
+        send_text_to_user(peers, uid, &text);
+    }
+}
+
+fn send_text_to_user(peers: &PeerMap, uid: Uuid, text: &str) {
+    if let Some(connections) = peers.get(&uid) {
+        for peer in connections.iter() {
+            let _ = peer.send_text(text);
+        }
+    }
+}
+
+/// Returns true if this was the user's first device, i.e. they just came online.
+fn register_peer(peers: &PeerMap, uid: Uuid, peer: PeerConnection) -> bool {
+    let mut connections = peers.entry(uid).or_default();
+    connections.push(peer);
+    connections.len() == 1
+}
+
+/// Removes a single device; returns true once the user's last connection is gone.
+/// None if that device wasn't registered, e.g. because it was already pruned.
+fn unregister_peer(peers: &PeerMap, uid: Uuid, connection_id: Uuid) -> Option<bool> {
+    let mut connections = peers.get_mut(&uid)?;
+    let before = connections.len();
+    connections.retain(|peer| peer.connection_id != connection_id);
+    if connections.len() == before {
+        return None;
+    }
+    drop(connections);
+    Some(peers.remove_if(&uid, |_, connections| connections.is_empty()).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_tokens::TokenMint;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use sink::Recorder;
+
+    fn test_state() -> AppState {
+        test_state_with(&[])
+    }
+
+    fn test_state_with(settings: &[(&str, &str)]) -> AppState {
+        let config = Config::from_lookup(|key| match key {
+            "JWT_SECRET" => Some("unit-test-secret-of-at-least-32-bytes".to_owned()),
+            _ => settings.iter().find(|(name, _)| *name == key).map(|(_, value)| value.to_string()),
+        })
+        .unwrap();
+        AppState::new(config, PrometheusBuilder::new().build_recorder().handle())
+    }
+
+    fn device(capacity: usize) -> (PeerConnection, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::new(capacity));
+        let info = Arc::new(ConnectionInfo {
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            user_agent: None,
+            connected_at: unix_millis(),
+            protocol: ProtocolVersion::V1,
+            traffic: Traffic::default(),
+        });
+        let tx = recorder.clone();
+        let peer = PeerConnection { connection_id: Uuid::new_v4(), tx, framing: Framing::Text, kicked: CancellationToken::new(), info };
+        (peer, recorder)
+    }
+
+    // A connected device that records everything routed to it
+    async fn connect_device(state: &AppState, uid: Uuid, capacity: usize) -> Arc<Recorder> {
+        let (peer, recorder) = device(capacity);
+        connect_peer(state, uid, peer).await;
+        recorder
+    }
+
+    // Runs one client message through an identified session and returns the server's reply, if any
+    async fn reply_to(state: &AppState, uid: Uuid, message: serde_json::Value) -> Option<serde_json::Value> {
+        let (me, recorder) = device(16);
+        let mut session = Session { user_id: Some(uid), ..Session::default() };
+        handle_text(&message.to_string(), state, &me, &mut session).await;
+        recorder.take().into_iter().next()
+    }
+
+    fn signal(target_id: Uuid, sender_id: Uuid) -> KodaSignal {
+        KodaSignal::Signal {
+            target_id,
+            sender_id: Some(sender_id),
+            data: serde_json::json!({ "sdp": "offer" }),
+            msg_id: None,
+            seq: None,
+            server_ts: None,
+            want_status: None,
+            priority: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn signalling_yourself_is_refused_with_self_target() {
+        let state = test_state();
+        let me = Uuid::new_v4();
+        let refused = check_route(&state, me, me).await.expect_err("self-targeted signal must be refused");
+        assert_eq!(refused, RoutingOutcome::SelfTarget);
+        let wire = serde_json::to_value(refused.reply(&state).unwrap()).unwrap();
+        assert_eq!(wire["type"], "ERROR");
+        assert_eq!(wire["payload"]["code"], "SELF_TARGET");
+    }
+
+    #[tokio::test]
+    async fn signalling_someone_else_is_allowed() {
+        assert_eq!(check_route(&test_state(), Uuid::new_v4(), Uuid::new_v4()).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn blocked_senders_are_refused_silently() {
+        let state = test_state();
+        let (sender, target) = (Uuid::new_v4(), Uuid::new_v4());
+        state.blocklist.block(target, sender);
+        let refused = check_route(&state, sender, target).await.unwrap_err();
+        assert_eq!(refused, RoutingOutcome::Blocked);
+        assert!(refused.reply(&state).is_none());
+    }
+
+    #[tokio::test]
+    async fn offline_target_reports_peer_offline() {
+        let state = test_state();
+        let target = Uuid::new_v4();
+        let outcome = route_signal(&state, target, signal(target, Uuid::new_v4())).await;
+        assert_eq!(outcome, RoutingOutcome::PeerOffline(target));
+        assert!(!outcome.delivered());
+    }
+
+    #[tokio::test]
+    async fn online_target_receives_the_signal() {
+        let state = test_state();
+        let (sender, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let device = connect_device(&state, target, 4).await;
+        let outcome = route_signal(&state, target, signal(target, sender)).await;
+        assert_eq!(outcome, RoutingOutcome::Delivered);
+        let [wire] = &device.take()[..] else { panic!("exactly one frame must be delivered") };
+        assert_eq!(wire["type"], "SIGNAL");
+        assert_eq!(wire["payload"]["sender_id"], sender.to_string());
+    }
+
+    #[tokio::test]
+    async fn identify_accepts_minted_tokens_and_names_each_rejection() {
+        let state = test_state();
+        let mint = TokenMint::for_config(&state.config);
+        let user_id = Uuid::new_v4();
+        let identify = |token: String| serde_json::json!({ "type": "IDENTIFY", "payload": { "token": token } }).to_string();
+        let first_reply = async |token: String| {
+            let (me, recorder) = device(8);
+            handle_text(&identify(token), &state, &me, &mut Session::default()).await;
+            recorder.take().remove(0)
+        };
+
+        let accepted = first_reply(mint.valid(user_id)).await;
+        assert_eq!(accepted["type"], "AUTHENTICATED");
+        assert_eq!(accepted["payload"]["user_id"], user_id.to_string());
+        assert_eq!(first_reply(mint.expiring_in(user_id, -3600)).await["payload"]["code"], "TOKEN_EXPIRED");
+        let early = first_reply(mint.not_yet_valid(user_id, 3600)).await;
+        assert_eq!(early["payload"]["code"], "TOKEN_NOT_YET_VALID");
+        let retry_after = early["payload"]["retry_after"].as_u64().unwrap();
+        assert!((3569..=3570).contains(&retry_after), "nbf minus the 30s leeway, got {}", retry_after);
+        let forged = TokenMint::new("some-other-secret-of-at-least-32-bytes").valid(user_id);
+        assert_eq!(first_reply(forged).await["payload"]["code"], "UNAUTHORIZED");
+    }
+
+    #[tokio::test]
+    async fn identifying_again_as_the_same_user_keeps_the_session() {
+        let state = test_state();
+        let mint = TokenMint::for_config(&state.config);
+        let (user_id, friend) = (Uuid::new_v4(), Uuid::new_v4());
+        let room_id = Uuid::new_v4();
+        let friend_device = connect_device(&state, friend, 8).await;
+        let identify = serde_json::json!({ "type": "IDENTIFY", "payload": { "token": mint.valid(user_id) } }).to_string();
+        let (me, recorder) = device(8);
+        let mut session = Session::default();
+        handle_text(&identify, &state, &me, &mut session).await;
+        reply_to(&state, friend, serde_json::json!({ "type": "SUBSCRIBE", "payload": { "peer_ids": [user_id] } })).await;
+        for uid in [user_id, friend] {
+            reply_to(&state, uid, serde_json::json!({ "type": "JOIN_ROOM", "payload": { "room_id": room_id } })).await;
+        }
+        friend_device.take();
+        recorder.take();
+
+        handle_text(&identify, &state, &me, &mut session).await;
+        let replies: Vec<_> = recorder.take().iter().map(|reply| reply["type"].clone()).collect();
+        assert_eq!(replies, ["AUTHENTICATED", "RESUME_TOKEN"], "a refresh is only acknowledged");
+        assert!(friend_device.take().is_empty(), "neither presence nor the room may see the user leave");
+        assert_eq!(state.peers.get(&user_id).map(|connections| connections.len()), Some(1));
+        assert!(state.rooms.is_member(room_id, user_id));
+    }
+
+    #[tokio::test]
+    async fn senders_who_ask_get_a_status_for_every_signal() {
+        let state = test_state();
+        let (sender, online, offline) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let target_device = connect_device(&state, online, 4).await;
+        let (me, recorder) = device(8);
+        let mut session = Session { user_id: Some(sender), ..Session::default() };
+        let signal_to = |target_id: Uuid| {
+            serde_json::json!({ "type": "SIGNAL", "payload": { "target_id": target_id, "data": { "sdp": "offer" }, "want_status": true } })
+        };
+
+        handle_text(&signal_to(online).to_string(), &state, &me, &mut session).await;
+        handle_text(&signal_to(offline).to_string(), &state, &me, &mut session).await;
+        let statuses: Vec<_> = recorder
+            .take()
+            .into_iter()
+            .filter(|frame| frame["type"] == "SIGNAL_STATUS")
+            .map(|frame| (frame["payload"]["target_id"].clone(), frame["payload"]["status"].clone()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [(online.to_string().into(), "DELIVERED".into()), (offline.to_string().into(), "OFFLINE".into())]
+        );
+        let [forwarded] = &target_device.take()[..] else { panic!("target must get the signal") };
+        assert!(forwarded["payload"].get("want_status").is_none(), "the flag is not forwarded: {}", forwarded);
+    }
+
+    #[tokio::test]
+    async fn target_with_full_queue_is_busy() {
+        let state = test_state();
+        let target = Uuid::new_v4();
+        let _device = connect_device(&state, target, 1).await;
+        assert_eq!(route_signal(&state, target, signal(target, Uuid::new_v4())).await, RoutingOutcome::Delivered);
+        assert_eq!(route_signal(&state, target, signal(target, Uuid::new_v4())).await, RoutingOutcome::PeerBusy);
+    }
+
+    #[tokio::test]
+    async fn target_that_hung_up_mid_route_is_offline() {
+        let state = test_state();
+        let target = Uuid::new_v4();
+        // Still registered, but its socket task is gone
+        connect_device(&state, target, 4).await.hang_up();
+        let outcome = route_signal(&state, target, signal(target, Uuid::new_v4())).await;
+        assert_eq!(outcome, RoutingOutcome::PeerOffline(target));
+        assert!(!state.peers.contains_key(&target), "the stale registration must be pruned");
+        assert_eq!(state.online_users.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn subscribing_past_the_cap_is_rejected_whole() {
+        let state = test_state_with(&[("MAX_SUBSCRIPTIONS_PER_USER", "3")]);
+        let me = Uuid::new_v4();
+        let subscribe = |count: usize| {
+            let peer_ids: Vec<Uuid> = (0..count).map(|_| Uuid::new_v4()).collect();
+            serde_json::json!({ "type": "SUBSCRIBE", "payload": { "peer_ids": peer_ids } })
+        };
+
+        assert_eq!(reply_to(&state, me, subscribe(2)).await, None);
+        let rejected = reply_to(&state, me, subscribe(2)).await.expect("over-cap subscribe must be answered");
+        assert_eq!(rejected["payload"]["code"], "LIMIT_EXCEEDED");
+        // Nothing from the rejected batch was kept, so one more still fits
+        assert_eq!(reply_to(&state, me, subscribe(1)).await, None);
+    }
+
+    #[tokio::test]
+    async fn joining_past_the_room_cap_is_rejected() {
+        let state = test_state_with(&[("MAX_ROOMS_PER_USER", "2")]);
+        let me = Uuid::new_v4();
+        let join = |room_id: Uuid| serde_json::json!({ "type": "JOIN_ROOM", "payload": { "room_id": room_id } });
+        let first = Uuid::new_v4();
+
+        for room_id in [first, Uuid::new_v4()] {
+            assert_eq!(reply_to(&state, me, join(room_id)).await.unwrap()["type"], "ROOM_MEMBERS");
+        }
+        let rejected = reply_to(&state, me, join(Uuid::new_v4())).await.unwrap();
+        assert_eq!(rejected["payload"]["code"], "LIMIT_EXCEEDED");
+        // Rejoining a room they're already in doesn't count against the cap
+        assert_eq!(reply_to(&state, me, join(first)).await.unwrap()["type"], "ROOM_MEMBERS");
+    }
+
+    #[tokio::test]
+    async fn signal_data_without_an_allowed_key_is_rejected() {
+        let state = test_state_with(&[("SIGNAL_DATA_KEYS", "sdp,candidate")]);
+        let (me, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let _target = connect_device(&state, target, 4).await;
+        let signal = |data: serde_json::Value| {
+            serde_json::json!({ "type": "SIGNAL", "payload": { "target_id": target, "data": data } })
+        };
+
+        assert_eq!(reply_to(&state, me, signal(serde_json::json!({ "candidate": "a=..." }))).await, None);
+        for data in [serde_json::json!({ "blob": "..." }), serde_json::json!("sdp")] {
+            let rejected = reply_to(&state, me, signal(data)).await.expect("disallowed data must be answered");
+            assert_eq!(rejected["payload"]["code"], "INVALID_SIGNAL_DATA");
+        }
+    }
+
+    #[tokio::test]
+    async fn status_text_reaches_subscribers_and_presence_queries() {
+        let state = test_state();
+        let (me, friend) = (Uuid::new_v4(), Uuid::new_v4());
+        let friend_device = connect_device(&state, friend, 4).await;
+        let _me_device = connect_device(&state, me, 4).await;
+        reply_to(&state, friend, serde_json::json!({ "type": "SUBSCRIBE", "payload": { "peer_ids": [me] } })).await;
+
+        let set = serde_json::json!({ "type": "SET_STATUS", "payload": { "status": "In a meeting" } });
+        assert_eq!(reply_to(&state, me, set).await, None);
+        let [update] = &friend_device.take()[..] else { panic!("subscriber must get exactly the new status") };
+        assert_eq!(update["type"], "PRESENCE_UPDATE");
+        assert_eq!(update["payload"]["status_text"], "In a meeting");
+
+        let query = serde_json::json!({ "type": "WHO_IS_ONLINE", "payload": { "peer_ids": [me] } });
+        let status = reply_to(&state, friend, query).await.unwrap();
+        assert_eq!(status["payload"]["status_text"][me.to_string()], "In a meeting");
+
+        let too_long = serde_json::json!({ "type": "SET_STATUS", "payload": { "status": "x".repeat(MAX_STATUS_TEXT_LEN + 1) } });
+        assert_eq!(reply_to(&state, me, too_long).await.unwrap()["payload"]["code"], "LIMIT_EXCEEDED");
+    }
+
+    #[tokio::test]
+    async fn disconnect_tells_subscribers_it_was_a_logout() {
+        let state = test_state();
+        let (me, friend) = (Uuid::new_v4(), Uuid::new_v4());
+        let friend_device = connect_device(&state, friend, 4).await;
+        let (device, _recorder) = device(4);
+        connect_peer(&state, me, device.clone()).await;
+        reply_to(&state, friend, serde_json::json!({ "type": "SUBSCRIBE", "payload": { "peer_ids": [me] } })).await;
+
+        let mut session = Session { user_id: Some(me), ..Session::default() };
+        let disconnect = serde_json::json!({ "type": "DISCONNECT", "payload": { "reason": null } });
+        handle_text(&disconnect.to_string(), &state, &device, &mut session).await;
+
+        assert!(session.user_id.is_none());
+        assert!(!state.peers.contains_key(&me));
+        let [update] = &friend_device.take()[..] else { panic!("subscriber must get exactly one update") };
+        assert_eq!(update["payload"]["status"], "OFFLINE");
+        assert_eq!(update["payload"]["reason"], "logout");
+    }
+
+    #[tokio::test]
+    async fn quick_reconnects_within_the_grace_period_are_invisible_to_subscribers() {
+        let state = test_state_with(&[("PRESENCE_GRACE_SECS", "60")]);
+        let (me, friend) = (Uuid::new_v4(), Uuid::new_v4());
+        let friend_device = connect_device(&state, friend, 4).await;
+        let (device, _recorder) = device(4);
+        connect_peer(&state, me, device.clone()).await;
+        reply_to(&state, friend, serde_json::json!({ "type": "SUBSCRIBE", "payload": { "peer_ids": [me] } })).await;
+
+        disconnect_peer(&state, me, device.connection_id, None).await;
+        let (device, _recorder) = self::device(4);
+        connect_peer(&state, me, device.clone()).await;
+        assert!(friend_device.take().is_empty(), "a network blip must not flap presence");
+
+        // Logging out isn't a blip, so it is announced straight away
+        disconnect_peer(&state, me, device.connection_id, Some("logout".to_owned())).await;
+        let [update] = &friend_device.take()[..] else { panic!("subscriber must get exactly one update") };
+        assert_eq!(update["payload"]["status"], "OFFLINE");
+    }
+
+    #[tokio::test]
+    async fn quick_reconnects_keep_rooms_blocks_and_subscriptions() {
+        let state = test_state_with(&[("PRESENCE_GRACE_SECS", "60")]);
+        let (me, friend, stranger, watched) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let room_id = Uuid::new_v4();
+        let friend_device = connect_device(&state, friend, 8).await;
+        let (device, _recorder) = device(8);
+        connect_peer(&state, me, device.clone()).await;
+        for uid in [me, friend] {
+            reply_to(&state, uid, serde_json::json!({ "type": "JOIN_ROOM", "payload": { "room_id": room_id } })).await;
+        }
+        reply_to(&state, me, serde_json::json!({ "type": "SUBSCRIBE", "payload": { "peer_ids": [watched] } })).await;
+        state.blocklist.block(me, stranger);
+        friend_device.take();
+
+        disconnect_peer(&state, me, device.connection_id, None).await;
+        let (device, _recorder) = self::device(8);
+        connect_peer(&state, me, device.clone()).await;
+        assert!(friend_device.take().is_empty(), "room members must not see a PEER_LEFT for a blip");
+        assert!(state.rooms.is_member(room_id, me));
+        assert!(state.blocklist.is_blocked(me, stranger));
+        assert_eq!(state.presence.subscribers_of(watched), [me]);
+
+        disconnect_peer(&state, me, device.connection_id, Some("logout".to_owned())).await;
+        let [left] = &friend_device.take()[..] else { panic!("a logout leaves the room at once") };
+        assert_eq!(left["type"], "PEER_LEFT");
+        assert!(!state.blocklist.is_blocked(me, stranger));
+    }
+
+    #[tokio::test]
+    async fn a_pruned_connection_is_reported_disconnected_once() {
+        let (tx, mut batches) = mpsc::unbounded_channel();
+        let receiver = Router::new().route(
+            "/events",
+            post(move |axum::Json(batch): axum::Json<Vec<serde_json::Value>>| async move {
+                tx.send(batch).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook = format!("http://{}/events", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+        let state = test_state_with(&[("EVENT_WEBHOOK_URL", &hook)]);
+        let target = Uuid::new_v4();
+        let (device, recorder) = device(4);
+        connect_peer(&state, target, device.clone()).await;
+
+        // Routing finds the dead socket first, then the socket's own exit path disconnects it again
+        recorder.hang_up();
+        route_signal(&state, target, signal(target, Uuid::new_v4())).await;
+        disconnect_peer(&state, target, device.connection_id, None).await;
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            let batch = time::timeout(Duration::from_secs(10), batches.recv()).await.expect("no events posted");
+            events.extend(batch.unwrap());
+        }
+        // Anything wrongly reported twice would have been posted in the same batch
+        let kinds: Vec<_> = events.iter().map(|event| event["event"].clone()).collect();
+        assert_eq!(kinds, ["connected", "disconnected"]);
+    }
+
+    #[tokio::test]
+    async fn capabilities_list_real_message_types_and_limits() {
+        let state = test_state();
+        let (me, recorder) = device(4);
+        let mut session = Session::default();
+        handle_text(r#"{"type":"CAPABILITIES"}"#, &state, &me, &mut session).await;
+        let [reply] = &recorder.take()[..] else { panic!("expected one reply before IDENTIFY") };
+        assert_eq!(reply["payload"]["protocol_version"], "koda.v1");
+        assert_eq!(reply["payload"]["limits"]["max_payload_bytes"], state.config.max_payload_bytes);
+
+        // The list is maintained by hand, so make sure every entry still parses as a message type
+        for name in CLIENT_MESSAGE_TYPES {
+            let error = serde_json::from_value::<KodaSignal>(serde_json::json!({ "type": name, "payload": {} }))
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default();
+            assert!(!error.contains("unknown variant"), "{} is not a message type: {}", name, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn room_signal_reaches_every_other_member_exactly_once() {
+        let state = test_state();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let room_id = Uuid::new_v4();
+        let devices = [connect_device(&state, alice, 8).await, connect_device(&state, bob, 8).await, connect_device(&state, carol, 8).await];
+        for uid in [alice, bob, carol] {
+            reply_to(&state, uid, serde_json::json!({ "type": "JOIN_ROOM", "payload": { "room_id": room_id } })).await;
+        }
+        for device in &devices {
+            device.take();
+        }
+
+        let room_signal = serde_json::json!({ "type": "ROOM_SIGNAL", "payload": { "room_id": room_id, "data": { "sdp": "offer" } } });
+        assert_eq!(reply_to(&state, alice, room_signal).await, None);
+        assert!(devices[0].take().is_empty(), "the sender must not get its own signal back");
+        for device in &devices[1..] {
+            let [wire] = &device.take()[..] else { panic!("each other member gets exactly one frame") };
+            assert_eq!(wire["type"], "ROOM_SIGNAL");
+            assert_eq!(wire["payload"]["sender_id"], alice.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn large_presence_fan_out_reaches_every_subscriber_once() {
+        let state = test_state();
+        let popular = Uuid::new_v4();
+        let mut subscribers = Vec::new();
+        for _ in 0..PRESENCE_FANOUT_CHUNK * 2 + 1 {
+            let fan = Uuid::new_v4();
+            subscribers.push(connect_device(&state, fan, 4).await);
+            state.presence.subscribe(fan, &[popular], 1);
+        }
+
+        connect_device(&state, popular, 4).await;
+        // Chunks after the first are paced, so give them a moment
+        time::sleep(PRESENCE_FANOUT_PAUSE * 20).await;
+        for fan in &subscribers {
+            let [update] = &fan.take()[..] else { panic!("every subscriber gets exactly one update") };
+            assert_eq!(update["payload"]["status"], "ONLINE");
+        }
+    }
+
+    #[tokio::test]
+    async fn signals_without_data_are_rejected_before_routing() {
+        let state = test_state();
+        let (sender, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let target_device = connect_device(&state, target, 8).await;
+        for data in [serde_json::Value::Null, serde_json::json!({}), serde_json::json!("")] {
+            let signal = serde_json::json!({ "type": "SIGNAL", "payload": { "target_id": target, "data": data } });
+            let reply = reply_to(&state, sender, signal).await.unwrap();
+            assert_eq!(reply["payload"]["code"], "EMPTY_SIGNAL_DATA", "for data {}", data);
+        }
+        assert!(target_device.take().is_empty(), "nothing may reach the target");
+
+        let signal = serde_json::json!({ "type": "SIGNAL", "payload": { "target_id": target, "data": { "sdp": "offer" } } });
+        assert_eq!(reply_to(&state, sender, signal).await, None);
+        assert_eq!(target_device.take().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn candidate_batches_arrive_as_one_frame_and_are_capped() {
+        let state = test_state();
+        let (sender, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let target_device = connect_device(&state, target, 4).await;
+        let batch = |count: usize| {
+            let candidates: Vec<_> = (0..count).map(|i| serde_json::json!({ "candidate": format!("candidate:{} 1 udp", i) })).collect();
+            serde_json::json!({ "type": "CANDIDATE_BATCH", "payload": { "target_id": target, "candidates": candidates } })
+        };
+
+        assert_eq!(reply_to(&state, sender, batch(3)).await, None);
+        let [wire] = &target_device.take()[..] else { panic!("the batch must stay a single frame") };
+        assert_eq!(wire["type"], "CANDIDATE_BATCH");
+        assert_eq!(wire["payload"]["sender_id"], sender.to_string());
+        assert_eq!(wire["payload"]["candidates"][2]["candidate"], "candidate:2 1 udp");
+
+        let too_many = reply_to(&state, sender, batch(MAX_CANDIDATE_BATCH + 1)).await.unwrap();
+        assert_eq!(too_many["payload"]["code"], "LIMIT_EXCEEDED");
+        assert!(target_device.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fan_out_never_echoes_to_the_senders_other_devices() {
+        let state = test_state();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let room_id = Uuid::new_v4();
+        let (phone, phone_recorder) = device(8);
+        connect_peer(&state, alice, phone.clone()).await;
+        let laptop = connect_device(&state, alice, 8).await;
+        let bob_device = connect_device(&state, bob, 8).await;
+        for uid in [alice, bob] {
+            reply_to(&state, uid, serde_json::json!({ "type": "JOIN_ROOM", "payload": { "room_id": room_id } })).await;
+        }
+        for recorder in [&phone_recorder, &laptop, &bob_device] {
+            recorder.take();
+        }
+
+        let mut session = Session { user_id: Some(alice), ..Session::default() };
+        let room_signal = serde_json::json!({ "type": "ROOM_SIGNAL", "payload": { "room_id": room_id, "data": { "sdp": "offer" } } });
+        handle_text(&room_signal.to_string(), &state, &phone, &mut session).await;
+        // Listing yourself among the targets must not reach your own devices either
+        let multi_signal = serde_json::json!({ "type": "MULTI_SIGNAL", "payload": { "target_ids": [alice, bob], "data": { "sdp": "offer" } } });
+        handle_text(&multi_signal.to_string(), &state, &phone, &mut session).await;
+
+        assert!(laptop.take().is_empty(), "the sender's other device must not see its own signals");
+        let [result] = &phone_recorder.take()[..] else { panic!("the sending device only gets the MULTI_SIGNAL summary") };
+        assert_eq!(result["type"], "MULTI_SIGNAL_RESULT");
+        let types: Vec<_> = bob_device.take().iter().map(|wire| wire["type"].clone()).collect();
+        assert_eq!(types, ["ROOM_SIGNAL", "SIGNAL"]);
+    }
+
+    #[test]
+    fn unserializable_frames_are_skipped_not_panicked_on() {
+        struct Unserializable;
+        impl Serialize for Unserializable {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("refuses to serialize"))
+            }
+        }
+        assert_eq!(to_json(&Unserializable), None);
+        assert_eq!(to_json(&KodaSignal::Heartbeat).as_deref(), Some(r#"{"type":"HEARTBEAT"}"#));
+    }
+
+    #[tokio::test]
+    async fn a_signal_refused_by_the_quota_is_not_a_duplicate_when_retried() {
+        let mut state = test_state_with(&[("DAILY_SIGNAL_QUOTA", "1"), ("SIGNAL_DEDUP_WINDOW_MS", "60000")]);
+        let (sender, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let target_device = connect_device(&state, target, 8).await;
+        let (me, recorder) = device(8);
+        let mut session = Session { user_id: Some(sender), dedup: Dedup::new(state.config.signal_dedup_window), ..Session::default() };
+        let send = |sdp: &str| {
+            let payload = serde_json::json!({ "target_id": target, "data": { "sdp": sdp }, "want_status": true });
+            serde_json::json!({ "type": "SIGNAL", "payload": payload }).to_string()
+        };
+
+        handle_text(&send("offer"), &state, &me, &mut session).await;
+        handle_text(&send("answer"), &state, &me, &mut session).await;
+        let statuses: Vec<_> = recorder.take().into_iter().filter(|wire| wire["type"] == "SIGNAL_STATUS").collect();
+        assert_eq!(statuses.last().unwrap()["payload"]["status"], "RATE_LIMITED");
+        assert_eq!(target_device.take().len(), 1);
+
+        // A fresh allowance, as after midnight UTC
+        state.quota = Arc::new(SignalQuota::new(1));
+        handle_text(&send("answer"), &state, &me, &mut session).await;
+        let [status] = &recorder.take()[..] else { panic!("the retry gets exactly one status") };
+        assert_eq!(status["payload"]["status"], "DELIVERED");
+        let [wire] = &target_device.take()[..] else { panic!("the retry must reach the target") };
+        assert_eq!(wire["payload"]["data"]["sdp"], "answer");
+    }
+
+    #[tokio::test]
+    async fn repeated_seq_is_dropped_without_using_quota() {
+        let state = test_state_with(&[("DAILY_SIGNAL_QUOTA", "2")]);
+        let (sender, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let target_device = connect_device(&state, target, 8).await;
+        let (me, recorder) = device(8);
+        let mut session = Session { user_id: Some(sender), ..Session::default() };
+        let send = |seq: u64| {
+            let payload = serde_json::json!({ "target_id": target, "data": { "seq": seq }, "seq": seq, "want_status": true });
+            serde_json::json!({ "type": "SIGNAL", "payload": payload }).to_string()
+        };
+
+        for seq in [1, 1, 2] {
+            handle_text(&send(seq), &state, &me, &mut session).await;
+        }
+        let statuses: Vec<_> = recorder.take().iter().map(|status| status["payload"]["status"].clone()).collect();
+        assert_eq!(statuses, ["DELIVERED", "REJECTED", "DELIVERED"]);
+        assert_eq!(target_device.take().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn multi_signal_only_charges_the_quota_for_routed_targets() {
+        let state = test_state_with(&[("DAILY_SIGNAL_QUOTA", "1")]);
+        let (sender, friend, blocker) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let friend_device = connect_device(&state, friend, 8).await;
+        let _blocker_device = connect_device(&state, blocker, 8).await;
+        state.blocklist.block(blocker, sender);
+
+        let multi_signal = serde_json::json!({
+            "type": "MULTI_SIGNAL",
+            "payload": { "target_ids": [sender, blocker, friend], "data": { "sdp": "offer" } }
+        });
+        let result = reply_to(&state, sender, multi_signal.clone()).await.unwrap();
+        assert_eq!(result["type"], "MULTI_SIGNAL_RESULT");
+        assert_eq!(result["payload"]["offline"], serde_json::json!([sender, blocker]));
+        assert_eq!(friend_device.take().len(), 1);
+        // The friend used up the allowance on their own
+        let refused = reply_to(&state, sender, multi_signal).await.unwrap();
+        assert_eq!(refused["payload"]["code"], "QUOTA_EXCEEDED");
+    }
+
+    #[tokio::test]
+    async fn a_filling_queue_refuses_low_priority_before_high() {
+        let state = test_state();
+        let target = Uuid::new_v4();
+        let _device = connect_device(&state, target, 4).await;
+        let prioritized = |priority| {
+            let mut routed = signal(target, Uuid::new_v4());
+            if let KodaSignal::Signal { priority: slot, .. } = &mut routed {
+                *slot = Some(priority);
+            }
+            routed
+        };
+
+        for _ in 0..2 {
+            assert_eq!(route_signal(&state, target, prioritized(Priority::Low)).await, RoutingOutcome::Delivered);
+        }
+        // Half full: no more room for Low, but Normal and High still get through
+        assert_eq!(route_signal(&state, target, prioritized(Priority::Low)).await, RoutingOutcome::PeerBusy);
+        assert_eq!(route_signal(&state, target, prioritized(Priority::Normal)).await, RoutingOutcome::Delivered);
+        assert_eq!(route_signal(&state, target, prioritized(Priority::High)).await, RoutingOutcome::Delivered);
+        assert_eq!(route_signal(&state, target, prioritized(Priority::High)).await, RoutingOutcome::PeerBusy);
+    }
+}
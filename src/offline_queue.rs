@@ -0,0 +1,216 @@
+use dashmap::DashMap;
+use metrics::counter;
+use redis::aio::ConnectionManager;
+use redis::{RedisResult, Script};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::protocol::{KodaSignal, Priority};
+
+// Same policy as the in-memory queue: drop expired entries from the front, then, if still full,
+// evict the oldest entry of the lowest priority below the newcomer's.
+// Returns -1 if the newcomer was refused, the evicted entry's rank, or 3 if nothing was evicted.
+const PUSH: &str = r#"
+local cutoff = tonumber(ARGV[5]) - tonumber(ARGV[4])
+while true do
+    local head = redis.call("LINDEX", KEYS[1], 0)
+    if not head or cjson.decode(head).queued_at >= cutoff then break end
+    redis.call("LPOP", KEYS[1])
+end
+local evicted = 3
+if redis.call("LLEN", KEYS[1]) >= tonumber(ARGV[3]) then
+    local victim, lowest = nil, tonumber(ARGV[2])
+    for _, entry in ipairs(redis.call("LRANGE", KEYS[1], 0, -1)) do
+        local rank = cjson.decode(entry).rank
+        if rank < lowest then victim, lowest = entry, rank end
+    end
+    if not victim then return -1 end
+    redis.call("LREM", KEYS[1], 1, victim)
+    evicted = lowest
+end
+redis.call("RPUSH", KEYS[1], ARGV[1])
+redis.call("PEXPIRE", KEYS[1], ARGV[4])
+return evicted
+"#;
+
+const PRIORITIES: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+
+fn queue_key(user_id: Uuid) -> String {
+    format!("koda:queue:{}", user_id)
+}
+
+// One list entry in Redis; the rank lets the push script compare priorities without knowing the protocol
+#[derive(Serialize, Deserialize)]
+struct Queued<S> {
+    queued_at: i64,
+    rank: u8,
+    signal: S,
+}
+
+// Holds signals for peers that are briefly offline (e.g. a reconnect blip mid call setup)
+pub struct OfflineQueue {
+    queues: DashMap<Uuid, VecDeque<(Instant, KodaSignal)>>,
+    ttl: Duration,
+    max_depth: usize,
+    // With Redis, queues survive restarts and any node can flush them; memory is only the fallback
+    redis: Option<ConnectionManager>,
+}
+
+impl OfflineQueue {
+    /// A `max_depth` of zero disables queueing entirely.
+    pub fn new(ttl: Duration, max_depth: usize) -> Self {
+        OfflineQueue { queues: DashMap::new(), ttl, max_depth, redis: None }
+    }
+
+    /// Keeps queues in Redis lists (`koda:queue:{user_id}`) instead of this node's memory.
+    pub fn durable(ttl: Duration, max_depth: usize, redis: ConnectionManager) -> Self {
+        OfflineQueue { redis: Some(redis), ..OfflineQueue::new(ttl, max_depth) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_depth > 0
+    }
+
+    /// Hands the signal back if it could not be held, so the caller falls back to PEER_OFFLINE.
+    /// A full queue makes room by evicting its oldest signal of lower priority, if it has one.
+    pub async fn push(&self, target_id: Uuid, signal: KodaSignal) -> Result<(), Box<KodaSignal>> {
+        if !self.is_enabled() {
+            return Err(Box::new(signal));
+        }
+        if let Some(redis) = &self.redis {
+            match self.push_durable(redis.clone(), target_id, &signal).await {
+                Ok(evicted) if evicted < 0 => return Err(Box::new(signal)),
+                Ok(evicted) => {
+                    if let Some(priority) = PRIORITIES.get(evicted as usize) {
+                        counter!("koda_signals_dropped_by_priority_total", "priority" => priority.as_str()).increment(1);
+                    }
+                    return Ok(());
+                }
+                // Held here instead; it is still flushed if the peer comes back to this node
+                Err(e) => warn!(%target_id, error = %e, "Failed to queue signal in Redis"),
+            }
+        }
+        let mut queue = self.queues.entry(target_id).or_default();
+        self.drop_expired(&mut queue);
+        if queue.len() >= self.max_depth {
+            let priority = signal.priority();
+            let lowest = queue
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, queued))| queued.priority() < priority)
+                .min_by_key(|(_, (_, queued))| queued.priority())
+                .map(|(index, _)| index);
+            let Some(evicted) = lowest.and_then(|index| queue.remove(index)) else { return Err(Box::new(signal)) };
+            counter!("koda_signals_dropped_by_priority_total", "priority" => evicted.1.priority().as_str()).increment(1);
+        }
+        queue.push_back((Instant::now(), signal));
+        Ok(())
+    }
+
+    async fn push_durable(&self, mut redis: ConnectionManager, target_id: Uuid, signal: &KodaSignal) -> RedisResult<i64> {
+        let now = crate::unix_millis();
+        let rank = signal.priority() as u8;
+        // Routed signals were already serialized once, so this can't fail in practice
+        let Some(entry) = crate::to_json(&Queued { queued_at: now, rank, signal }) else { return Ok(-1) };
+        Script::new(PUSH)
+            .key(queue_key(target_id))
+            .arg(entry)
+            .arg(rank)
+            .arg(self.max_depth)
+            .arg(self.ttl.as_millis() as u64)
+            .arg(now)
+            .invoke_async(&mut redis)
+            .await
+    }
+
+    /// Removes and returns every non-expired signal for `target_id`, oldest first.
+    pub async fn drain(&self, target_id: Uuid) -> Vec<KodaSignal> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+        let mut drained = match &self.redis {
+            Some(redis) => self.drain_durable(redis.clone(), target_id).await,
+            None => Vec::new(),
+        };
+        if let Some((_, mut queue)) = self.queues.remove(&target_id) {
+            self.drop_expired(&mut queue);
+            drained.extend(queue.into_iter().map(|(_, signal)| signal));
+        }
+        drained
+    }
+
+    async fn drain_durable(&self, mut redis: ConnectionManager, target_id: Uuid) -> Vec<KodaSignal> {
+        let key = queue_key(target_id);
+        // Read and delete in one transaction so two nodes can't both flush the same signal
+        let drained: RedisResult<(Vec<String>,)> =
+            redis::pipe().atomic().lrange(&key, 0, -1).del(&key).ignore().query_async(&mut redis).await;
+        let entries = match drained {
+            Ok((entries,)) => entries,
+            Err(e) => {
+                warn!(%target_id, error = %e, "Failed to drain queued signals from Redis");
+                return Vec::new();
+            }
+        };
+        let cutoff = crate::unix_millis() - self.ttl.as_millis() as i64;
+        entries
+            .iter()
+            .filter_map(|entry| serde_json::from_str::<Queued<KodaSignal>>(entry).ok())
+            .filter(|queued| queued.queued_at >= cutoff)
+            .map(|queued| queued.signal)
+            .collect()
+    }
+
+    // Targets that never come back would otherwise keep their queue forever; Redis keys expire on their own
+    pub fn prune(&self) {
+        self.queues.retain(|_, queue| {
+            self.drop_expired(queue);
+            !queue.is_empty()
+        });
+    }
+
+    fn drop_expired(&self, queue: &mut VecDeque<(Instant, KodaSignal)>) {
+        while queue.front().is_some_and(|(queued_at, _)| queued_at.elapsed() > self.ttl) {
+            queue.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Priority;
+
+    fn signal(priority: Priority) -> KodaSignal {
+        KodaSignal::Signal {
+            target_id: Uuid::new_v4(),
+            sender_id: None,
+            data: serde_json::Value::Null,
+            msg_id: None,
+            seq: None,
+            server_ts: None,
+            want_status: None,
+            priority: Some(priority),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_evicts_the_oldest_lower_priority_signal() {
+        let queue = OfflineQueue::new(Duration::from_secs(30), 3);
+        let target = Uuid::new_v4();
+        for priority in [Priority::Normal, Priority::Low, Priority::Low] {
+            assert!(queue.push(target, signal(priority)).await.is_ok());
+        }
+
+        assert!(queue.push(target, signal(Priority::High)).await.is_ok());
+        // Nothing left below Normal except the younger Low, so a second Normal evicts it
+        assert!(queue.push(target, signal(Priority::Normal)).await.is_ok());
+        assert!(queue.push(target, signal(Priority::Normal)).await.is_err(), "equal priority never evicts");
+
+        let kept: Vec<Priority> = queue.drain(target).await.iter().map(KodaSignal::priority).collect();
+        assert_eq!(kept, [Priority::Normal, Priority::High, Priority::Normal]);
+    }
+}
@@ -0,0 +1,163 @@
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+use uuid::Uuid;
+
+// The node doesn't know the friend graph, so clients explicitly register who they watch
+#[derive(Default)]
+pub struct Presence {
+    // watched user -> users subscribed to them
+    subscribers: DashMap<Uuid, HashSet<Uuid>>,
+    // subscriber -> users they watch, so cleanup doesn't scan every entry
+    subscriptions: DashMap<Uuid, HashSet<Uuid>>,
+    // user -> unix millis when their last device disconnected
+    last_seen: DashMap<Uuid, i64>,
+    // offline user -> senders to tell once when they come back
+    awaiting: DashMap<Uuid, HashSet<Uuid>>,
+    // sender -> offline users they are waiting on, for cleanup
+    waiting_on: DashMap<Uuid, HashSet<Uuid>>,
+    // user -> custom status text, while they are online
+    status_text: DashMap<Uuid, String>,
+    // user whose last device just dropped -> token of their held-back offline notice
+    reconnecting: DashMap<Uuid, Uuid>,
+}
+
+impl Presence {
+    /// Adds all of `peer_ids` or, if that would take `subscriber` past `max` watched users, none of them.
+    /// The cap is per user, so all of a user's devices share it.
+    pub fn subscribe(&self, subscriber: Uuid, peer_ids: &[Uuid], max: usize) -> bool {
+        let mut watched = self.subscriptions.entry(subscriber).or_default();
+        let added: HashSet<Uuid> = peer_ids
+            .iter()
+            .copied()
+            .filter(|peer_id| *peer_id != subscriber && !watched.contains(peer_id))
+            .collect();
+        let accepted = watched.len() + added.len() <= max;
+        if accepted {
+            for peer_id in added {
+                watched.insert(peer_id);
+                self.subscribers.entry(peer_id).or_default().insert(subscriber);
+            }
+        }
+        // A refused or empty request mustn't leave an entry behind for every caller
+        let empty = watched.is_empty();
+        drop(watched);
+        if empty {
+            self.subscriptions.remove_if(&subscriber, |_, watched| watched.is_empty());
+        }
+        accepted
+    }
+
+    pub fn subscribers_of(&self, user_id: Uuid) -> Vec<Uuid> {
+        self.subscribers
+            .get(&user_id)
+            .map(|set| set.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn await_online(&self, sender: Uuid, peer_id: Uuid) {
+        if self.waiting_on.entry(sender).or_default().insert(peer_id) {
+            self.awaiting.entry(peer_id).or_default().insert(sender);
+        }
+    }
+
+    /// Removes and returns everyone waiting for `peer_id`, so each is told only once.
+    pub fn take_awaiting(&self, peer_id: Uuid) -> Vec<Uuid> {
+        let Some((_, senders)) = self.awaiting.remove(&peer_id) else { return Vec::new() };
+        for sender in &senders {
+            if let Some(mut set) = self.waiting_on.get_mut(sender) {
+                set.remove(&peer_id);
+            }
+            self.waiting_on.remove_if(sender, |_, set| set.is_empty());
+        }
+        senders.into_iter().collect()
+    }
+
+    /// An empty `text` clears the status.
+    pub fn set_status_text(&self, user_id: Uuid, text: String) {
+        if text.is_empty() {
+            self.status_text.remove(&user_id);
+        } else {
+            self.status_text.insert(user_id, text);
+        }
+    }
+
+    pub fn status_text(&self, user_id: Uuid) -> Option<String> {
+        self.status_text.get(&user_id).map(|text| text.clone())
+    }
+
+    /// Holds back the offline notice for `user_id`; returns the token `grace_expired` needs.
+    pub fn begin_grace(&self, user_id: Uuid) -> Uuid {
+        let token = Uuid::new_v4();
+        self.reconnecting.insert(user_id, token);
+        token
+    }
+
+    /// True if `user_id` came back in time, in which case subscribers never saw them leave.
+    pub fn end_grace(&self, user_id: Uuid) -> bool {
+        self.reconnecting.remove(&user_id).is_some()
+    }
+
+    /// True if the grace started with `token` ran out without `user_id` coming back,
+    /// so the offline notice is now due.
+    pub fn grace_expired(&self, user_id: Uuid, token: Uuid) -> bool {
+        self.reconnecting.remove_if(&user_id, |_, pending| *pending == token).is_some()
+    }
+
+    pub fn record_last_seen(&self, user_id: Uuid) {
+        self.last_seen.insert(user_id, crate::unix_millis());
+    }
+
+    pub fn last_seen(&self, user_id: Uuid) -> Option<i64> {
+        self.last_seen.get(&user_id).map(|seen| *seen)
+    }
+
+    // Users who never come back would otherwise be remembered forever
+    pub fn prune_last_seen(&self, horizon: Duration) {
+        let cutoff = crate::unix_millis() - horizon.as_millis() as i64;
+        self.last_seen.retain(|_, seen| *seen >= cutoff);
+    }
+
+    /// Drops every subscription, pending online notice and the status text held by `subscriber`;
+    /// called once their last device leaves.
+    pub fn unsubscribe_all(&self, subscriber: Uuid) {
+        self.status_text.remove(&subscriber);
+        if let Some((_, watched)) = self.subscriptions.remove(&subscriber) {
+            for peer_id in watched {
+                if let Some(mut set) = self.subscribers.get_mut(&peer_id) {
+                    set.remove(&subscriber);
+                }
+                self.subscribers.remove_if(&peer_id, |_, set| set.is_empty());
+            }
+        }
+        if let Some((_, awaited)) = self.waiting_on.remove(&subscriber) {
+            for peer_id in awaited {
+                if let Some(mut set) = self.awaiting.get_mut(&peer_id) {
+                    set.remove(&subscriber);
+                }
+                self.awaiting.remove_if(&peer_id, |_, set| set.is_empty());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refused_subscriptions_leave_nothing_behind() {
+        let presence = Presence::default();
+        let subscriber = Uuid::new_v4();
+        let peer_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        assert!(!presence.subscribe(subscriber, &peer_ids, 2));
+        assert!(presence.subscribe(subscriber, &[subscriber], 2), "watching yourself is ignored, not refused");
+        assert!(presence.subscriptions.is_empty());
+        assert!(presence.subscribers.is_empty());
+
+        assert!(presence.subscribe(subscriber, &peer_ids[..2], 2));
+        assert!(!presence.subscribe(subscriber, &peer_ids[2..], 2));
+        assert_eq!(presence.subscriptions.get(&subscriber).map(|watched| watched.len()), Some(2));
+        assert_eq!(presence.subscribers_of(peer_ids[0]), [subscriber]);
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -6,17 +7,381 @@ use uuid::Uuid;
 pub enum KodaSignal {
     // 1. Handshake: Client sends JWT immediately upon connecting
     Identify { token: String },
+    // Swap in a fresh token for the same user without reconnecting
+    Reidentify { token: String },
+    // Re-bind a reconnecting socket to its dropped session using a token from RESUME_TOKEN
+    Resume { resume_token: String },
+    // Deliberate logout, as opposed to the socket just dropping; the server closes the socket after it
+    Disconnect { reason: Option<String> },
     
     // 2. Signaling: Passing WebRTC/MoQ data
     // target_id is the Friend's UUID from koda-api
     Signal { 
         target_id: Uuid, 
         sender_id: Option<Uuid>, // Filled by the server for security
-        data: serde_json::Value  // The actual SDP or ICE candidate
+        data: serde_json::Value, // The actual SDP or ICE candidate
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<Uuid>,    // Opt-in: the server answers with an ACK for this id
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,        // Opt-in: forwarded in increasing order per target, duplicates dropped
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_ts: Option<i64>,  // Unix millis when the node forwarded it; ignored from clients
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        want_status: Option<bool>, // Opt-in: the server answers with SIGNAL_STATUS; never forwarded
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        priority: Option<Priority> // Under queue pressure lower priorities are refused first; Normal if unset
     },
+    // For integrations that only know a username or email; resolved by the server, then routed as a Signal
+    SignalByHandle { handle: String, data: serde_json::Value },
+    // Mesh calls: the same data to several peers, expanded by the server into one Signal each
+    MultiSignal { target_ids: Vec<Uuid>, data: serde_json::Value },
+    // Explicit call teardown, routed exactly like Signal
+    Hangup {
+        target_id: Uuid,
+        sender_id: Option<Uuid>, // Filled by the server for security
+        reason: Option<String>   // e.g. "normal" vs "network_failure"
+    },
+    // Several trickled ICE candidates in one frame, routed to the target as a single message
+    CandidateBatch {
+        target_id: Uuid,
+        sender_id: Option<Uuid>, // Filled by the server for security
+        candidates: Vec<serde_json::Value>
+    },
+
+    // Non-critical UI events (typing, reactions); `kind` namespaces them
+    Ephemeral {
+        target_id: Uuid,
+        sender_id: Option<Uuid>, // Filled by the server for security
+        kind: String,
+        data: serde_json::Value
+    },
+
+    // 3. Presence: register interest in peers, then receive their online/offline transitions
+    Subscribe { peer_ids: Vec<Uuid> },
+    PresenceUpdate {
+        user_id: Uuid,
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status_text: Option<String>, // Custom status of an online user, if they set one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String> // Only on Offline after a DISCONNECT; absent when the connection was lost
+    },
+    SetStatus { status: String }, // Shown to subscribers until changed or the last device leaves; "" clears it
+    WhoIsOnline { peer_ids: Vec<Uuid> },
+    OnlineStatus {
+        online: Vec<Uuid>,
+        offline: Vec<Uuid>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        last_seen: HashMap<Uuid, i64>, // Unix millis, for offline peers this node has seen leave
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        status_text: HashMap<Uuid, String> // Custom status, for online peers that set one
+    },
+
+    // 4. Rooms: small group calls, fanned out to every other member
+    JoinRoom { room_id: Uuid },
+    LeaveRoom { room_id: Uuid },
+    RoomSignal {
+        room_id: Uuid,
+        sender_id: Option<Uuid>, // Filled by the server for security
+        data: serde_json::Value
+    },
+    RoomMembers { room_id: Uuid, members: Vec<Uuid> }, // Sent to a newcomer: everyone already there
+    PeerJoined { room_id: Uuid, peer_id: Uuid },
+    PeerLeft { room_id: Uuid, peer_id: Uuid },
+
+    // 5. Safety: refuse signals from specific peers for the rest of the session
+    Block { peer_id: Uuid },
+    Unblock { peer_id: Uuid },
+
+    // 6. System: Server sending updates to the client
+    Authenticated { user_id: Uuid, connection_id: Uuid }, // connection_id is per socket; quote it when reporting issues
+    IceServers { servers: Vec<IceServer> }, // Sent right after AUTHENTICATED when STUN/TURN is configured
+    ResumeToken { resume_token: String },   // Single use; replaces any token sent before on this socket
+    PeerOffline {
+        peer_id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen: Option<i64> // Unix millis when their last device left, if this node remembers
+    },
+    PeerOnline { peer_id: Uuid }, // One-shot: a peer you signaled while they were offline is back
+    MultiSignalResult { offline: Vec<Uuid> }, // Targets of a MULTI_SIGNAL that didn't get it live
+    Ack { msg_id: Uuid, delivered: bool }, // Best-effort; false if queued, dropped or offline
+    // What became of one SIGNAL sent with `want_status`
+    SignalStatus {
+        target_id: Uuid,
+        status: DeliveryStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<Uuid> // Echoed when the signal had one
+    },
+    // Node is going away; reconnect elsewhere before it closes
+    ServerShutdown {
+        drain_seconds: u64,
+        reconnect_after_ms: u64, // Jittered per connection so clients don't all reconnect at once
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        redirect_url: Option<String> // Another node to reconnect to, if the operator named one
+    },
+    Announcement { message: String, severity: Severity }, // Operator notice sent to everyone
+    // Application-level keepalive for clients behind proxies that strip control-frame pings
+    Heartbeat,
+    HeartbeatAck { server_ts: i64 },
+    // Introspection for client developers; the answer only changes with the node's version or config
+    Capabilities,
+    CapabilitiesResult {
+        protocol_version: ProtocolVersion,
+        supported_types: Vec<String>, // Message types a client may send
+        limits: Limits
+    },
+    Error {
+        code: ErrorCode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>, // Human-readable detail, may change between releases
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reset_at: Option<i64>, // Unix millis when a QUOTA_EXCEEDED allowance refills
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64> // Seconds until a TOKEN_NOT_YET_VALID token will be accepted
+    }
+}
+
+/// Wire names of the messages a client may send, as reported by CAPABILITIES_RESULT.
+pub const CLIENT_MESSAGE_TYPES: [&str; 20] = [
+    "IDENTIFY",
+    "REIDENTIFY",
+    "RESUME",
+    "DISCONNECT",
+    "SIGNAL",
+    "SIGNAL_BY_HANDLE",
+    "MULTI_SIGNAL",
+    "HANGUP",
+    "CANDIDATE_BATCH",
+    "EPHEMERAL",
+    "SUBSCRIBE",
+    "SET_STATUS",
+    "WHO_IS_ONLINE",
+    "JOIN_ROOM",
+    "LEAVE_ROOM",
+    "ROOM_SIGNAL",
+    "BLOCK",
+    "UNBLOCK",
+    "HEARTBEAT",
+    "CAPABILITIES",
+];
+
+/// The node's active limits, so clients can stay under them instead of learning them from errors.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub max_payload_bytes: usize, // Serialized `data` of one signal
+    pub max_message_bytes: usize,
+    pub rate_limit_per_sec: f64,
+    pub rate_limit_burst: f64,
+    pub max_multi_signal_targets: usize,
+    pub max_candidate_batch: usize,
+    pub max_presence_query: usize,
+    pub max_subscriptions_per_user: usize,
+    pub max_room_members: usize,
+    pub max_rooms_per_user: usize,
+    pub max_status_text_len: usize,
+    pub daily_signal_quota: u64, // 0 when there is no daily cap
+}
+
+/// Protocol revisions negotiated through `Sec-WebSocket-Protocol`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    #[serde(rename = "koda.v1")]
+    V1,
+}
+
+impl ProtocolVersion {
+    // Ordered by preference when a client offers several
+    pub const SUPPORTED: [ProtocolVersion; 1] = [ProtocolVersion::V1];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ProtocolVersion::V1 => "koda.v1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::SUPPORTED.into_iter().find(|version| version.name() == name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DeliveryStatus {
+    Delivered, // A live socket accepted it, here or on another node
+    Queued,
+    Offline,
+    Busy,
+    Blocked, // Only with REVEAL_BLOCKS; otherwise reported as Rejected
+    RateLimited,
+    Rejected, // Refused before routing: invalid, oversized, a duplicate, or not allowed
+}
+
+// Ordered lowest first, so comparisons read naturally
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Priority {
+    Low, // e.g. a late trickle ICE candidate
+    #[default]
+    Normal,
+    High, // e.g. the initial SDP offer or answer
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PresenceStatus {
+    Online,
+    Offline,
+}
+
+// Shaped like the browser's RTCIceServer so clients can pass it straight to RTCPeerConnection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+// Stable, machine-readable error codes; clients should branch on these, never on `message`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    IdentifyRequired,
+    #[serde(rename = "MALFORMATTED_JSON")] // Wire name predates the enum
+    MalformedJson,
+    PeerOffline,
+    Unauthorized,
+    RateLimited,
+    AuthTimeout,
+    TokenExpired,
+    InvalidToken,
+    PeerBusy,
+    LimitExceeded,
+    NotFriends,
+    PayloadTooLarge,
+    IdentityMismatch,
+    Kicked,
+    TooManyConnections,
+    Blocked,
+    IdleTimeout,
+    RoomFull,
+    NotInRoom,
+    UnknownMessageType,
+    ResumeFailed,
+    SelfTarget,
+    SenderIdNotAllowed,
+    SlowConsumer,
+    InvalidSignalData,
+    TooManyAuthAttempts,
+    QuotaExceeded,
+    SessionExpired,
+    TokenNotYetValid,
+    EmptySignalData,
+    UnknownHandle,
+}
+
+impl KodaSignal {
+    pub fn error(code: ErrorCode) -> Self {
+        KodaSignal::Error { code, message: None, reset_at: None, retry_after: None }
+    }
+
+    /// Only signals carry a priority; everything else routes as Normal.
+    pub fn priority(&self) -> Priority {
+        match self {
+            KodaSignal::Signal { priority, .. } => priority.unwrap_or_default(),
+            _ => Priority::Normal,
+        }
+    }
+
+    /// True if a client filled in a `sender_id` that only the server may set.
+    pub fn claims_sender(&self) -> bool {
+        matches!(
+            self,
+            KodaSignal::Signal { sender_id: Some(_), .. }
+                | KodaSignal::Hangup { sender_id: Some(_), .. }
+                | KodaSignal::CandidateBatch { sender_id: Some(_), .. }
+                | KodaSignal::Ephemeral { sender_id: Some(_), .. }
+                | KodaSignal::RoomSignal { sender_id: Some(_), .. }
+        )
+    }
+
+    /// Client messages that are only accepted on an identified socket.
+    pub fn requires_identity(&self) -> bool {
+        matches!(
+            self,
+            KodaSignal::Reidentify { .. }
+                | KodaSignal::Signal { .. }
+                | KodaSignal::SignalByHandle { .. }
+                | KodaSignal::MultiSignal { .. }
+                | KodaSignal::Hangup { .. }
+                | KodaSignal::CandidateBatch { .. }
+                | KodaSignal::Ephemeral { .. }
+                | KodaSignal::Subscribe { .. }
+                | KodaSignal::SetStatus { .. }
+                | KodaSignal::WhoIsOnline { .. }
+                | KodaSignal::JoinRoom { .. }
+                | KodaSignal::LeaveRoom { .. }
+                | KodaSignal::RoomSignal { .. }
+                | KodaSignal::Block { .. }
+                | KodaSignal::Unblock { .. }
+        )
+    }
+}
+
+/// WebSocket close codes for server-initiated disconnects, in the private 4000–4999 range.
+///
+/// | Code | Reason |
+/// | --- | --- |
+/// | 4001 | Authentication failed, timed out, or the session's token or lifetime expired |
+/// | 4002 | Kicked by an operator |
+/// | 4003 | Idle for longer than `IDLE_TIMEOUT_SECS` |
+/// | 4004 | Kept sending while rate-limited |
+/// | 4005 | Node is shutting down; reconnect elsewhere |
+///
+/// Any other policy violation closes with the standard 1008. The close reason is the
+/// matching `ErrorCode` name, or `SERVER_SHUTDOWN`.
+pub mod close_codes {
+    pub const AUTH_FAILED: u16 = 4001;
+    pub const KICKED: u16 = 4002;
+    pub const IDLE: u16 = 4003;
+    pub const RATE_LIMITED: u16 = 4004;
+    pub const SERVER_SHUTDOWN: u16 = 4005;
+    pub const POLICY: u16 = 1008;
+}
 
-    // 3. System: Server sending updates to the client
-    Authenticated { user_id: Uuid },
-    PeerOffline { peer_id: Uuid },
-    Error { message: String }
+impl ErrorCode {
+    pub fn close_code(self) -> u16 {
+        match self {
+            ErrorCode::Unauthorized
+            | ErrorCode::AuthTimeout
+            | ErrorCode::TokenExpired
+            | ErrorCode::InvalidToken
+            | ErrorCode::TooManyAuthAttempts
+            | ErrorCode::SessionExpired => close_codes::AUTH_FAILED,
+            ErrorCode::Kicked => close_codes::KICKED,
+            ErrorCode::IdleTimeout => close_codes::IDLE,
+            ErrorCode::RateLimited => close_codes::RATE_LIMITED,
+            _ => close_codes::POLICY,
+        }
+    }
 }
@@ -0,0 +1,118 @@
+use dashmap::DashMap;
+use redis::aio::ConnectionManager;
+use redis::RedisResult;
+use tracing::warn;
+use uuid::Uuid;
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+fn quota_key(user_id: Uuid, day: i64) -> String {
+    format!("koda:quota:{}:{}", user_id, day)
+}
+
+/// Caps how many signals each user may route per UTC day, on top of the per-socket rate limiter.
+///
+/// Counts reset at midnight UTC. With Redis the count is shared by every node; otherwise each
+/// node counts only what it routed, and a restart forgets it.
+pub struct SignalQuota {
+    limit: u64,
+    // user -> (day number, signals used that day)
+    used: DashMap<Uuid, (i64, u64)>,
+    redis: Option<ConnectionManager>,
+}
+
+impl SignalQuota {
+    /// A `limit` of zero disables the quota.
+    pub fn new(limit: u64) -> Self {
+        SignalQuota { limit, used: DashMap::new(), redis: None }
+    }
+
+    pub fn durable(limit: u64, redis: ConnectionManager) -> Self {
+        SignalQuota { redis: Some(redis), ..SignalQuota::new(limit) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// Takes `count` signals from the user's allowance, all or nothing.
+    /// On refusal returns when the allowance resets, in Unix millis.
+    pub async fn consume(&self, user_id: Uuid, count: u64) -> Result<(), i64> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let now = crate::unix_millis();
+        if let Some(redis) = &self.redis {
+            match self.consume_durable(redis.clone(), user_id, count, now).await {
+                Ok(consumed) => return consumed,
+                // Counting locally keeps the cap roughly in place until Redis is back
+                Err(e) => warn!(%user_id, error = %e, "Failed to count signal quota in Redis"),
+            }
+        }
+        self.consume_at(user_id, count, now)
+    }
+
+    async fn consume_durable(
+        &self,
+        mut redis: ConnectionManager,
+        user_id: Uuid,
+        count: u64,
+        now: i64,
+    ) -> RedisResult<Result<(), i64>> {
+        let day = now.div_euclid(DAY_MILLIS);
+        let reset_at = (day + 1) * DAY_MILLIS;
+        let key = quota_key(user_id, day);
+        let (used,): (u64,) = redis::pipe()
+            .atomic()
+            .incr(&key, count)
+            .pexpire_at(&key, reset_at)
+            .ignore()
+            .query_async(&mut redis)
+            .await?;
+        if used <= self.limit {
+            return Ok(Ok(()));
+        }
+        // Refunded so a refused MULTI_SIGNAL doesn't eat the rest of the allowance
+        redis::cmd("DECRBY").arg(&key).arg(count).query_async::<()>(&mut redis).await?;
+        Ok(Err(reset_at))
+    }
+
+    fn consume_at(&self, user_id: Uuid, count: u64, now: i64) -> Result<(), i64> {
+        let day = now.div_euclid(DAY_MILLIS);
+        let mut entry = self.used.entry(user_id).or_insert((day, 0));
+        let (counted_day, used) = &mut *entry;
+        if *counted_day != day {
+            *counted_day = day;
+            *used = 0;
+        }
+        if *used + count > self.limit {
+            return Err((day + 1) * DAY_MILLIS);
+        }
+        *used += count;
+        Ok(())
+    }
+
+    // Yesterday's counts are dead weight once the day rolls over
+    pub fn prune(&self) {
+        let today = crate::unix_millis().div_euclid(DAY_MILLIS);
+        self.used.retain(|_, (day, _)| *day == today);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowance_is_taken_all_or_nothing_and_resets_at_midnight() {
+        let quota = SignalQuota::new(3);
+        let user = Uuid::new_v4();
+        let noon = 20_000 * DAY_MILLIS + DAY_MILLIS / 2;
+
+        assert_eq!(quota.consume_at(user, 2, noon), Ok(()));
+        assert_eq!(quota.consume_at(user, 2, noon), Err(20_001 * DAY_MILLIS), "must not overdraw");
+        assert_eq!(quota.consume_at(user, 1, noon), Ok(()), "a refused batch takes nothing");
+        assert!(quota.consume_at(user, 1, noon).is_err());
+        assert_eq!(quota.consume_at(user, 3, noon + DAY_MILLIS), Ok(()));
+    }
+}
@@ -0,0 +1,30 @@
+use tokio::time::Instant;
+
+// Classic token bucket: refills continuously at `rate` tokens/sec up to `burst`
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket { rate, burst, tokens: burst, last_refill: Instant::now() }
+    }
+
+    /// Takes one token if available; returns false when the caller should be throttled.
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::admin::constant_time_eq;
+use crate::config::Config;
+use crate::{AppState, Delivery};
+
+/// Forwards frames for users this node doesn't hold to sibling nodes over HTTP.
+///
+/// A stopgap for small fixed deployments without Redis: every sibling is simply asked at once.
+pub struct HttpRelay {
+    client: reqwest::Client,
+    siblings: Vec<String>,
+    secret: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RelayRequest {
+    target_id: Uuid,
+    // Already sender-stamped and serialized, delivered verbatim
+    message: String,
+}
+
+impl HttpRelay {
+    /// Returns None unless `RELAY_NODES` is set.
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.relay_nodes.is_empty() {
+            return None;
+        }
+        Some(HttpRelay {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(2))
+                .build()
+                .expect("failed to build HTTP client"),
+            siblings: config.relay_nodes.iter().map(|url| url.trim_end_matches('/').to_owned()).collect(),
+            // Config::from_env requires RELAY_SECRET whenever RELAY_NODES is set
+            secret: config.relay_secret.clone()?,
+        })
+    }
+
+    /// Returns true once a sibling reports that it delivered the frame.
+    pub async fn relay(&self, target_id: Uuid, text: &str) -> bool {
+        let request = RelayRequest { target_id, message: text.to_owned() };
+        // Concurrently, so one unreachable sibling costs a single timeout rather than one per sibling
+        let mut attempts: FuturesUnordered<_> = self.siblings.iter().map(|sibling| self.post(sibling, &request)).collect();
+        while let Some(delivered) = attempts.next().await {
+            if delivered {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn post(&self, sibling: &str, request: &RelayRequest) -> bool {
+        let sent = self
+            .client
+            .post(format!("{}/relay", sibling))
+            .bearer_auth(&self.secret)
+            .json(request)
+            .send()
+            .await;
+        match sent {
+            Ok(response) if response.status().is_success() => true,
+            // Not connected there
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => false,
+            Ok(response) => {
+                warn!(%sibling, status = %response.status(), "Sibling refused relayed signal");
+                false
+            }
+            Err(e) => {
+                warn!(%sibling, error = %e, "Failed to relay signal over HTTP");
+                false
+            }
+        }
+    }
+}
+
+/// Delivers a frame relayed by a sibling to the target's local devices. Never relays further,
+/// so two nodes can't bounce a frame between them.
+pub async fn receive(State(state): State<AppState>, headers: HeaderMap, Json(request): Json<RelayRequest>) -> StatusCode {
+    // Without RELAY_SECRET the endpoint is switched off entirely
+    let Some(expected) = &state.config.relay_secret else {
+        return StatusCode::NOT_FOUND;
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match crate::deliver_local(&state.peers, request.target_id, &request.message) {
+        Some(Delivery::Delivered) => StatusCode::OK,
+        Some(Delivery::Busy) => StatusCode::SERVICE_UNAVAILABLE,
+        Some(Delivery::Closed) | None => {
+            debug!(target_id = %request.target_id, "Relayed signal for a user not on this node");
+            StatusCode::NOT_FOUND
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use dashmap::DashMap;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::auth::Claims;
+use crate::config::Config;
+
+/// Short-lived tokens that let a reconnecting client re-bind to its session without the JWT.
+///
+/// A token is handed out as soon as a session starts, because a flaky network rarely leaves
+/// a chance to deliver it at disconnect. It stays redeemable, once, while its socket is up and
+/// for `RESUME_TTL_SECS` after it drops, but never past the original JWT's `exp`.
+pub struct ResumeTokens {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    ttl: Duration,
+    // nonce -> what the token resumes
+    grants: DashMap<Uuid, Grant>,
+}
+
+struct Grant {
+    claims: Claims,
+    connection_id: Uuid,
+    released_at: Option<Instant>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResumeClaims {
+    sub: Uuid,
+    nonce: Uuid,
+    exp: usize,
+}
+
+pub struct Resumed {
+    pub claims: Claims,
+    // The socket the token was issued to, which may not have noticed it is dead yet
+    pub connection_id: Uuid,
+}
+
+impl ResumeTokens {
+    pub fn new(config: &Config) -> Self {
+        // Grants live in this process only, so a random key works when there is no shared secret
+        let secret = match &config.jwt_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => [Uuid::new_v4().into_bytes(), Uuid::new_v4().into_bytes()].concat(),
+        };
+        ResumeTokens {
+            encoding: EncodingKey::from_secret(&secret),
+            decoding: DecodingKey::from_secret(&secret),
+            validation: Validation::new(Algorithm::HS256),
+            ttl: config.resume_ttl,
+            grants: DashMap::new(),
+        }
+    }
+
+    /// A TTL of zero disables session resume entirely.
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Returns the grant's nonce and the token to hand to the client.
+    pub fn issue(&self, claims: &Claims, connection_id: Uuid) -> (Uuid, String) {
+        let nonce = Uuid::new_v4();
+        let token_claims = ResumeClaims { sub: claims.sub, nonce, exp: claims.exp };
+        let token = encode(&Header::new(Algorithm::HS256), &token_claims, &self.encoding)
+            .expect("HS256 encoding of resume claims cannot fail");
+        let grant = Grant { claims: claims.clone(), connection_id, released_at: None };
+        self.grants.insert(nonce, grant);
+        (nonce, token)
+    }
+
+    pub fn revoke(&self, nonce: Uuid) {
+        self.grants.remove(&nonce);
+    }
+
+    /// Starts the grant's countdown; called when its socket goes away.
+    pub fn release(&self, nonce: Uuid) {
+        if let Some(mut grant) = self.grants.get_mut(&nonce) {
+            grant.released_at = Some(Instant::now());
+        }
+    }
+
+    /// Consumes the token; None if it is forged, used, expired or its JWT has lapsed.
+    pub fn redeem(&self, token: &str) -> Option<Resumed> {
+        let token_claims = decode::<ResumeClaims>(token, &self.decoding, &self.validation).ok()?.claims;
+        let (_, grant) = self.grants.remove(&token_claims.nonce)?;
+        if grant.claims.sub != token_claims.sub || self.is_expired(&grant) {
+            return None;
+        }
+        Some(Resumed { claims: grant.claims, connection_id: grant.connection_id })
+    }
+
+    // Released grants nobody came back for would otherwise pile up
+    pub fn prune(&self) {
+        self.grants.retain(|_, grant| !self.is_expired(grant) && !grant.claims.expires_in(Duration::ZERO).is_zero());
+    }
+
+    fn is_expired(&self, grant: &Grant) -> bool {
+        grant.released_at.is_some_and(|released| released.elapsed() > self.ttl)
+    }
+}
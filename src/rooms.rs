@@ -0,0 +1,87 @@
+use dashmap::DashMap;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+pub enum Join {
+    // Members that were already in the room, excluding the newcomer
+    Joined(Vec<Uuid>),
+    AlreadyMember(Vec<Uuid>),
+    Full,
+    // The user is already in as many rooms as they may be
+    TooManyRooms,
+}
+
+// Rooms exist implicitly while they have members and are local to this node
+#[derive(Default)]
+pub struct Rooms {
+    // room -> members
+    members: DashMap<Uuid, HashSet<Uuid>>,
+    // member -> rooms, so cleanup doesn't scan every room
+    memberships: DashMap<Uuid, HashSet<Uuid>>,
+}
+
+impl Rooms {
+    pub fn join(&self, room_id: Uuid, user_id: Uuid, max_members: usize, max_rooms: usize) -> Join {
+        // Holding the room's entry makes the size check and insert atomic
+        let mut members = self.members.entry(room_id).or_default();
+        let others = members.iter().copied().filter(|&member| member != user_id).collect();
+        if members.contains(&user_id) {
+            return Join::AlreadyMember(others);
+        }
+        if members.len() >= max_members {
+            return Join::Full;
+        }
+        let mut rooms = self.memberships.entry(user_id).or_default();
+        if rooms.len() >= max_rooms {
+            drop(rooms);
+            drop(members);
+            // Don't leave behind the empty room the entry above may have created
+            self.members.remove_if(&room_id, |_, members| members.is_empty());
+            return Join::TooManyRooms;
+        }
+        rooms.insert(room_id);
+        members.insert(user_id);
+        Join::Joined(others)
+    }
+
+    /// Returns the members left behind, or None if `user_id` wasn't in the room.
+    pub fn leave(&self, room_id: Uuid, user_id: Uuid) -> Option<Vec<Uuid>> {
+        if let Some(mut rooms) = self.memberships.get_mut(&user_id) {
+            rooms.remove(&room_id);
+        }
+        self.memberships.remove_if(&user_id, |_, rooms| rooms.is_empty());
+        self.remove_member(room_id, user_id)
+    }
+
+    /// Drops `user_id` from every room; called once their last device leaves.
+    pub fn leave_all(&self, user_id: Uuid) -> Vec<(Uuid, Vec<Uuid>)> {
+        let Some((_, rooms)) = self.memberships.remove(&user_id) else { return Vec::new() };
+        rooms
+            .into_iter()
+            .filter_map(|room_id| self.remove_member(room_id, user_id).map(|remaining| (room_id, remaining)))
+            .collect()
+    }
+
+    pub fn is_member(&self, room_id: Uuid, user_id: Uuid) -> bool {
+        self.members.get(&room_id).is_some_and(|members| members.contains(&user_id))
+    }
+
+    pub fn members_of(&self, room_id: Uuid) -> Vec<Uuid> {
+        self.members
+            .get(&room_id)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn remove_member(&self, room_id: Uuid, user_id: Uuid) -> Option<Vec<Uuid>> {
+        let remaining = {
+            let mut members = self.members.get_mut(&room_id)?;
+            if !members.remove(&user_id) {
+                return None;
+            }
+            members.iter().copied().collect()
+        };
+        self.members.remove_if(&room_id, |_, members| members.is_empty());
+        Some(remaining)
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashMap};
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+// How long a gap may hold back later signals before we give up on the missing one
+const GAP_WAIT: Duration = Duration::from_millis(250);
+// Past this many held-back signals per target the gap is skipped immediately
+const MAX_BUFFERED: usize = 32;
+
+/// Restores client-assigned `seq` order for one connection's signals, per target.
+///
+/// Owned by the connection's read loop, so no locking is needed. A target's sequence starts
+/// at whatever `seq` the first signal to it carries.
+pub struct Sequencer<T> {
+    streams: HashMap<Uuid, Stream<T>>,
+}
+
+struct Stream<T> {
+    next: u64,
+    pending: BTreeMap<u64, T>,
+    gap_deadline: Option<Instant>,
+}
+
+impl<T> Default for Sequencer<T> {
+    fn default() -> Self {
+        Sequencer { streams: HashMap::new() }
+    }
+}
+
+impl<T> Sequencer<T> {
+    /// Returns what can be forwarded now, in order, or None if `seq` was already seen.
+    /// `u64::MAX` is refused too, since no later `seq` could follow it.
+    pub fn accept(&mut self, target_id: Uuid, seq: u64, item: T, now: Instant) -> Option<Vec<T>> {
+        if self.is_repeat(target_id, seq) {
+            return None;
+        }
+        let stream = self.streams.entry(target_id).or_insert_with(|| Stream {
+            next: seq,
+            pending: BTreeMap::new(),
+            gap_deadline: None,
+        });
+        stream.pending.insert(seq, item);
+        let mut ready = stream.drain_in_order();
+        if stream.pending.len() > MAX_BUFFERED {
+            ready.extend(stream.skip_gap());
+        }
+        if !ready.is_empty() {
+            // Progress was made; whatever still waits is behind a new gap
+            stream.gap_deadline = None;
+        }
+        stream.arm(now);
+        Some(ready)
+    }
+
+    /// True if `accept` would refuse `seq`, so the caller can drop it before spending anything on it.
+    pub fn is_repeat(&self, target_id: Uuid, seq: u64) -> bool {
+        // Keeps `next` from wrapping to 0, which would let every earlier `seq` through again
+        seq == u64::MAX
+            || self.streams.get(&target_id).is_some_and(|stream| seq < stream.next || stream.pending.contains_key(&seq))
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.streams.values().filter_map(|stream| stream.gap_deadline).min()
+    }
+
+    /// Gives up on gaps that outlived GAP_WAIT and releases what was waiting behind them.
+    pub fn expire(&mut self, now: Instant) -> Vec<(Uuid, T)> {
+        let mut ready = Vec::new();
+        for (&target_id, stream) in &mut self.streams {
+            if stream.gap_deadline.is_some_and(|deadline| deadline <= now) {
+                stream.gap_deadline = None;
+                ready.extend(stream.skip_gap().into_iter().map(|item| (target_id, item)));
+                stream.arm(now);
+            }
+        }
+        ready
+    }
+}
+
+impl<T> Stream<T> {
+    fn drain_in_order(&mut self) -> Vec<T> {
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.push(item);
+            let Some(next) = self.next.checked_add(1) else { break };
+            self.next = next;
+        }
+        ready
+    }
+
+    fn skip_gap(&mut self) -> Vec<T> {
+        match self.pending.keys().next() {
+            Some(&first) => {
+                self.next = first;
+                self.drain_in_order()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    // A gap's clock starts when something first has to wait behind it
+    fn arm(&mut self, now: Instant) {
+        if self.pending.is_empty() {
+            self.gap_deadline = None;
+        } else if self.gap_deadline.is_none() {
+            self.gap_deadline = Some(now + GAP_WAIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_signals_wait_for_the_gap_to_fill() {
+        let mut sequencer = Sequencer::default();
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        assert_eq!(sequencer.accept(target, 1, "a", now), Some(vec!["a"]));
+        assert_eq!(sequencer.accept(target, 3, "c", now), Some(vec![]));
+        assert_eq!(sequencer.next_deadline(), Some(now + GAP_WAIT));
+        assert_eq!(sequencer.accept(target, 2, "b", now), Some(vec!["b", "c"]));
+        assert_eq!(sequencer.next_deadline(), None);
+    }
+
+    #[test]
+    fn repeated_or_stale_seq_is_refused() {
+        let mut sequencer = Sequencer::default();
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        assert!(!sequencer.is_repeat(target, 5));
+        assert_eq!(sequencer.accept(target, 5, "a", now), Some(vec!["a"]));
+        assert!(sequencer.is_repeat(target, 5));
+        assert_eq!(sequencer.accept(target, 5, "a", now), None);
+        assert_eq!(sequencer.accept(target, 4, "z", now), None);
+        // Held back behind a gap counts as seen too
+        assert_eq!(sequencer.accept(target, 7, "c", now), Some(vec![]));
+        assert_eq!(sequencer.accept(target, 7, "c", now), None);
+    }
+
+    #[test]
+    fn gap_is_given_up_on_after_gap_wait() {
+        let mut sequencer = Sequencer::default();
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        sequencer.accept(target, 1, "a", now);
+        sequencer.accept(target, 3, "c", now);
+        assert!(sequencer.expire(now + GAP_WAIT - Duration::from_millis(1)).is_empty());
+        assert_eq!(sequencer.expire(now + GAP_WAIT), vec![(target, "c")]);
+        assert_eq!(sequencer.next_deadline(), None);
+        // The missing one is now stale
+        assert_eq!(sequencer.accept(target, 2, "b", now + GAP_WAIT), None);
+    }
+
+    #[test]
+    fn too_many_held_back_skips_the_gap_at_once() {
+        let mut sequencer = Sequencer::default();
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        sequencer.accept(target, 0, 0, now);
+        for seq in 2..2 + MAX_BUFFERED as u64 {
+            assert_eq!(sequencer.accept(target, seq, seq, now), Some(vec![]));
+        }
+        let last = 2 + MAX_BUFFERED as u64;
+        let released = sequencer.accept(target, last, last, now).unwrap();
+        assert_eq!(released, (2..=last).collect::<Vec<_>>());
+        assert_eq!(sequencer.next_deadline(), None);
+    }
+
+    #[test]
+    fn targets_are_sequenced_independently() {
+        let mut sequencer = Sequencer::default();
+        let (alice, bob, now) = (Uuid::new_v4(), Uuid::new_v4(), Instant::now());
+        sequencer.accept(alice, 1, "a1", now);
+        assert_eq!(sequencer.accept(alice, 3, "a3", now), Some(vec![]));
+        // Bob's stream starts at his own first seq and isn't held up by Alice's gap
+        assert_eq!(sequencer.accept(bob, 10, "b10", now), Some(vec!["b10"]));
+        assert_eq!(sequencer.accept(bob, 1, "b1", now), None);
+        assert_eq!(sequencer.expire(now + GAP_WAIT), vec![(alice, "a3")]);
+    }
+
+    #[test]
+    fn max_seq_is_refused_instead_of_wrapping() {
+        let mut sequencer = Sequencer::default();
+        let (target, now) = (Uuid::new_v4(), Instant::now());
+        assert_eq!(sequencer.accept(target, u64::MAX - 1, "a", now), Some(vec!["a"]));
+        assert_eq!(sequencer.accept(target, u64::MAX, "b", now), None);
+        assert_eq!(sequencer.accept(target, 0, "c", now), None);
+        assert_eq!(sequencer.accept(target, u64::MAX - 1, "a", now), None);
+    }
+}
@@ -0,0 +1,98 @@
+use axum::extract::ws::Message;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Where a connection's outbound frames go. Routing only ever pushes into a sink; the socket's
+/// send task owns the other end and writes to the WebSocket.
+pub trait PeerSink: Send + Sync {
+    /// Never waits: a full sink is the caller's backpressure signal.
+    fn try_send(&self, msg: Message) -> Result<(), TrySendError<Message>>;
+    fn is_closed(&self) -> bool;
+    /// Frames accepted but not yet written.
+    fn queue_depth(&self) -> usize;
+    fn capacity(&self) -> usize;
+}
+
+impl PeerSink for mpsc::Sender<Message> {
+    fn try_send(&self, msg: Message) -> Result<(), TrySendError<Message>> {
+        mpsc::Sender::try_send(self, msg)
+    }
+
+    fn is_closed(&self) -> bool {
+        mpsc::Sender::is_closed(self)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.max_capacity() - mpsc::Sender::capacity(self)
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_capacity()
+    }
+}
+
+#[cfg(test)]
+pub use recorder::Recorder;
+
+#[cfg(test)]
+mod recorder {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    /// Keeps every frame it accepts so tests can assert exactly what was sent, with no socket or timing.
+    pub struct Recorder {
+        frames: Mutex<Vec<Message>>,
+        capacity: usize,
+        closed: AtomicBool,
+    }
+
+    impl Recorder {
+        pub fn new(capacity: usize) -> Self {
+            Recorder { frames: Mutex::new(Vec::new()), capacity, closed: AtomicBool::new(false) }
+        }
+
+        /// Behaves like a socket whose task has exited.
+        pub fn hang_up(&self) {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+
+        /// Removes and parses the text frames sent so far.
+        pub fn take(&self) -> Vec<serde_json::Value> {
+            self.frames
+                .lock()
+                .unwrap()
+                .drain(..)
+                .filter_map(|frame| match frame {
+                    Message::Text(text) => Some(serde_json::from_str(&text).unwrap()),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    impl PeerSink for Recorder {
+        fn try_send(&self, msg: Message) -> Result<(), TrySendError<Message>> {
+            if self.is_closed() {
+                return Err(TrySendError::Closed(msg));
+            }
+            let mut frames = self.frames.lock().unwrap();
+            if frames.len() >= self.capacity {
+                return Err(TrySendError::Full(msg));
+            }
+            frames.push(msg);
+            Ok(())
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::Relaxed)
+        }
+
+        fn queue_depth(&self) -> usize {
+            self.frames.lock().unwrap().len()
+        }
+
+        fn capacity(&self) -> usize {
+            self.capacity
+        }
+    }
+}
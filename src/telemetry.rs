@@ -0,0 +1,33 @@
+use axum::extract::State;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::Duration;
+use tokio::time;
+
+use crate::AppState;
+
+// Signaling frames are small; anything in the upper buckets is worth a look
+const PAYLOAD_BUCKETS: &[f64] = &[128.0, 512.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0];
+
+pub fn install_recorder() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full("koda_signal_payload_bytes".into()), PAYLOAD_BUCKETS)
+        .expect("payload buckets must not be empty")
+        .install_recorder()
+        .expect("failed to install Prometheus recorder");
+
+    // Without the HTTP listener feature nobody drains histogram samples for us
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut tick = time::interval(Duration::from_secs(5));
+        loop {
+            tick.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    handle
+}
+
+pub async fn metrics(State(state): State<AppState>) -> String {
+    state.metrics.render()
+}
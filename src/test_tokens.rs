@@ -0,0 +1,46 @@
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// Mints HS256 tokens the node under test accepts, plus the near misses it must refuse.
+pub struct TokenMint {
+    key: EncodingKey,
+}
+
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+impl TokenMint {
+    pub fn new(secret: &str) -> Self {
+        TokenMint { key: EncodingKey::from_secret(secret.as_bytes()) }
+    }
+
+    /// Signs with the node's own `JWT_SECRET`.
+    pub fn for_config(config: &Config) -> Self {
+        TokenMint::new(config.jwt_secret.as_deref().expect("test config must use JWT_SECRET"))
+    }
+
+    /// Any claims at all, for tests about audience, issuer or the subject claim.
+    pub fn sign(&self, claims: Value) -> String {
+        encode(&Header::default(), &claims, &self.key).unwrap()
+    }
+
+    /// An hour-long token for `user_id`.
+    pub fn valid(&self, user_id: Uuid) -> String {
+        self.expiring_in(user_id, 3600)
+    }
+
+    /// `exp` relative to now, in seconds; negative for a token that has already expired.
+    pub fn expiring_in(&self, user_id: Uuid, secs: i64) -> String {
+        self.sign(json!({ "sub": user_id, "exp": now() + secs }))
+    }
+
+    /// Signed correctly but only usable `secs` from now.
+    pub fn not_yet_valid(&self, user_id: Uuid, secs: i64) -> String {
+        self.sign(json!({ "sub": user_id, "exp": now() + 3600, "nbf": now() + secs }))
+    }
+}
@@ -0,0 +1,59 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hmac::{Hmac, KeyInit, Mac};
+use sha1::Sha1;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::protocol::IceServer;
+
+/// Hands out STUN/TURN servers, minting time-limited TURN credentials per user
+/// using the TURN REST API scheme shared with coturn's `use-auth-secret`.
+pub struct IceConfig {
+    stun_urls: Vec<String>,
+    turn_urls: Vec<String>,
+    turn_secret: Option<String>,
+    credential_ttl: Duration,
+}
+
+impl IceConfig {
+    pub fn new(config: &Config) -> Self {
+        IceConfig {
+            stun_urls: config.stun_urls.clone(),
+            turn_urls: config.turn_urls.clone(),
+            turn_secret: config.turn_secret.clone(),
+            credential_ttl: config.turn_credential_ttl,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stun_urls.is_empty() && self.turn_urls.is_empty()
+    }
+
+    pub fn servers_for(&self, user_id: Uuid) -> Vec<IceServer> {
+        let mut servers = Vec::new();
+        if !self.stun_urls.is_empty() {
+            servers.push(IceServer { urls: self.stun_urls.clone(), username: None, credential: None });
+        }
+        // Config::from_env requires TURN_SECRET whenever TURN_URLS is set
+        if let (false, Some(secret)) = (self.turn_urls.is_empty(), &self.turn_secret) {
+            let expiry = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default() + self.credential_ttl;
+            let username = format!("{}:{}", expiry.as_secs(), user_id);
+            let credential = turn_credential(secret, &username);
+            servers.push(IceServer {
+                urls: self.turn_urls.clone(),
+                username: Some(username),
+                credential: Some(credential),
+            });
+        }
+        servers
+    }
+}
+
+// base64(HMAC-SHA1(secret, username)), which the TURN server recomputes to check the password
+fn turn_credential(secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
@@ -0,0 +1,465 @@
+use futures::{SinkExt, StreamExt};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use koda_signal_ch::config::Config;
+use koda_signal_ch::{build_app, AppState};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+const SECRET: &str = "integration-test-secret-of-32-bytes";
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Starts a node on an ephemeral port. Resume tokens are off so replies arrive in a fixed order.
+async fn spawn_node() -> SocketAddr {
+    spawn_node_with(&[]).await
+}
+
+async fn spawn_node_with(settings: &[(&str, String)]) -> SocketAddr {
+    let config = Config::from_lookup(|key| match key {
+        "JWT_SECRET" => Some(SECRET.to_owned()),
+        "RESUME_TTL_SECS" => Some("0".to_owned()),
+        _ => settings.iter().find(|(name, _)| *name == key).map(|(_, value)| value.clone()),
+    })
+    .expect("test config must be valid");
+    // A local recorder: only one global recorder may be installed per test binary
+    let metrics = PrometheusBuilder::new().build_recorder().handle();
+    let app = build_app(AppState::new(config, metrics));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+fn token_for(user_id: Uuid) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let claims = json!({ "sub": user_id, "exp": now + 3600 });
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+}
+
+async fn connect(addr: SocketAddr) -> Client {
+    let (client, _) = connect_async(format!("ws://{}/pulse", addr)).await.expect("upgrade failed");
+    client
+}
+
+async fn send(client: &mut Client, frame: impl Into<String>) {
+    client.send(Message::text(frame.into())).await.unwrap();
+}
+
+/// Next JSON message from the node, skipping control frames.
+async fn recv(client: &mut Client) -> Value {
+    loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for the node")
+            .expect("socket closed")
+            .unwrap();
+        if let Message::Text(text) = frame {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+async fn identify(client: &mut Client, user_id: Uuid) {
+    send(client, json!({ "type": "IDENTIFY", "payload": { "token": token_for(user_id) } }).to_string()).await;
+    let reply = recv(client).await;
+    assert_eq!(reply["type"], "AUTHENTICATED", "unexpected reply: {}", reply);
+    assert_eq!(reply["payload"]["user_id"], user_id.to_string());
+    assert!(reply["payload"]["connection_id"].as_str().and_then(|id| Uuid::parse_str(id).ok()).is_some());
+}
+
+fn signal_to(target_id: Uuid) -> String {
+    json!({ "type": "SIGNAL", "payload": { "target_id": target_id, "data": { "sdp": "offer" } } }).to_string()
+}
+
+#[tokio::test]
+async fn identify_with_valid_token_is_authenticated() {
+    let addr = spawn_node().await;
+    let mut client = connect(addr).await;
+    identify(&mut client, Uuid::new_v4()).await;
+}
+
+#[tokio::test]
+async fn signal_reaches_online_peer_with_sender_stamped() {
+    let addr = spawn_node().await;
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut alice_client = connect(addr).await;
+    let mut bob_client = connect(addr).await;
+    identify(&mut alice_client, alice).await;
+    identify(&mut bob_client, bob).await;
+
+    send(&mut alice_client, signal_to(bob)).await;
+
+    let received = recv(&mut bob_client).await;
+    assert_eq!(received["type"], "SIGNAL");
+    assert_eq!(received["payload"]["sender_id"], alice.to_string());
+    assert_eq!(received["payload"]["data"]["sdp"], "offer");
+}
+
+#[tokio::test]
+async fn forged_sender_id_is_rejected_and_not_routed() {
+    let addr = spawn_node().await;
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut alice_client = connect(addr).await;
+    let mut bob_client = connect(addr).await;
+    identify(&mut alice_client, alice).await;
+    identify(&mut bob_client, bob).await;
+
+    let forged = json!({
+        "type": "SIGNAL",
+        "payload": { "target_id": bob, "sender_id": Uuid::new_v4(), "data": { "sdp": "offer" } }
+    });
+    send(&mut alice_client, forged.to_string()).await;
+    let reply = recv(&mut alice_client).await;
+    assert_eq!(reply["type"], "ERROR");
+    assert_eq!(reply["payload"]["code"], "SENDER_ID_NOT_ALLOWED");
+
+    // An explicit null is the same as leaving it out, and gets the server-derived id
+    let unset = json!({
+        "type": "SIGNAL",
+        "payload": { "target_id": bob, "sender_id": null, "data": { "sdp": "answer" } }
+    });
+    send(&mut alice_client, unset.to_string()).await;
+    let received = recv(&mut bob_client).await;
+    assert_eq!(received["payload"]["sender_id"], alice.to_string());
+    assert_eq!(received["payload"]["data"]["sdp"], "answer", "the forged signal must not have been routed");
+}
+
+#[tokio::test]
+async fn signal_to_offline_peer_reports_peer_offline() {
+    let addr = spawn_node().await;
+    let mut client = connect(addr).await;
+    identify(&mut client, Uuid::new_v4()).await;
+
+    let offline = Uuid::new_v4();
+    send(&mut client, signal_to(offline)).await;
+    let reply = recv(&mut client).await;
+    assert_eq!(reply["type"], "PEER_OFFLINE");
+    assert_eq!(reply["payload"]["peer_id"], offline.to_string());
+}
+
+#[tokio::test]
+async fn sender_is_told_when_offline_peer_comes_online() {
+    let addr = spawn_node().await;
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut alice_client = connect(addr).await;
+    identify(&mut alice_client, alice).await;
+    send(&mut alice_client, signal_to(bob)).await;
+    assert_eq!(recv(&mut alice_client).await["type"], "PEER_OFFLINE");
+
+    let mut bob_client = connect(addr).await;
+    identify(&mut bob_client, bob).await;
+    let notice = recv(&mut alice_client).await;
+    assert_eq!(notice["type"], "PEER_ONLINE");
+    assert_eq!(notice["payload"]["peer_id"], bob.to_string());
+}
+
+#[tokio::test]
+async fn multi_signal_fans_out_and_reports_unreachable_targets() {
+    let addr = spawn_node().await;
+    let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let mut alice_client = connect(addr).await;
+    let mut bob_client = connect(addr).await;
+    identify(&mut alice_client, alice).await;
+    identify(&mut bob_client, bob).await;
+
+    let multi = json!({ "type": "MULTI_SIGNAL", "payload": { "target_ids": [bob, carol], "data": { "sdp": "offer" } } });
+    send(&mut alice_client, multi.to_string()).await;
+
+    let received = recv(&mut bob_client).await;
+    assert_eq!(received["type"], "SIGNAL");
+    assert_eq!(received["payload"]["sender_id"], alice.to_string());
+    let result = recv(&mut alice_client).await;
+    assert_eq!(result["type"], "MULTI_SIGNAL_RESULT");
+    assert_eq!(result["payload"]["offline"], json!([carol]));
+}
+
+#[tokio::test]
+async fn signal_before_identify_requires_identify() {
+    let addr = spawn_node().await;
+    let mut client = connect(addr).await;
+
+    send(&mut client, signal_to(Uuid::new_v4())).await;
+    let reply = recv(&mut client).await;
+    assert_eq!(reply["type"], "ERROR");
+    assert_eq!(reply["payload"]["code"], "IDENTIFY_REQUIRED");
+}
+
+#[tokio::test]
+async fn malformed_json_is_reported() {
+    let addr = spawn_node().await;
+    let mut client = connect(addr).await;
+
+    send(&mut client, "{not json").await;
+    let reply = recv(&mut client).await;
+    assert_eq!(reply["type"], "ERROR");
+    assert_eq!(reply["payload"]["code"], "MALFORMATTED_JSON");
+}
+
+#[tokio::test]
+async fn upgrade_negotiates_a_supported_protocol_version() {
+    let addr = spawn_node().await;
+    let request = |protocols: &str| {
+        let mut request = format!("ws://{}/pulse", addr).into_client_request().unwrap();
+        request.headers_mut().insert("sec-websocket-protocol", protocols.parse().unwrap());
+        request
+    };
+
+    let (mut client, response) = connect_async(request("koda.v9, koda.v1")).await.expect("v1 must be accepted");
+    assert_eq!(response.headers()["sec-websocket-protocol"], "koda.v1");
+    identify(&mut client, Uuid::new_v4()).await;
+
+    let rejected = connect_async(request("koda.v9")).await.expect_err("unknown versions must be refused");
+    let tokio_tungstenite::tungstenite::Error::Http(response) = rejected else { panic!("expected an HTTP refusal") };
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn require_secure_refuses_upgrades_the_proxy_did_not_mark_https() {
+    let addr = spawn_node_with(&[("REQUIRE_SECURE", "true".to_owned())]).await;
+    let request = |proto: &str| {
+        let mut request = format!("ws://{}/pulse", addr).into_client_request().unwrap();
+        request.headers_mut().insert("x-forwarded-proto", proto.parse().unwrap());
+        request
+    };
+
+    let (mut client, _) = connect_async(request("https")).await.expect("https-forwarded upgrades must be accepted");
+    identify(&mut client, Uuid::new_v4()).await;
+
+    for refused in [connect_async(request("http")).await, connect_async(format!("ws://{}/pulse", addr)).await] {
+        let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = refused else { panic!("expected an HTTP refusal") };
+        assert_eq!(response.status(), 400);
+    }
+}
+
+#[tokio::test]
+async fn signal_for_a_sibling_node_is_relayed_over_http() {
+    let relay_secret = "shared-relay-secret";
+    let sibling = spawn_node_with(&[("RELAY_SECRET", relay_secret.to_owned())]).await;
+    let node = spawn_node_with(&[
+        ("RELAY_NODES", format!("http://{}", sibling)),
+        ("RELAY_SECRET", relay_secret.to_owned()),
+    ])
+    .await;
+    let (alice_id, bob_id) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut alice = connect(node).await;
+    identify(&mut alice, alice_id).await;
+    let mut bob = connect(sibling).await;
+    identify(&mut bob, bob_id).await;
+
+    send(&mut alice, signal_to(bob_id)).await;
+    let received = recv(&mut bob).await;
+    assert_eq!(received["type"], "SIGNAL");
+    assert_eq!(received["payload"]["sender_id"], alice_id.to_string());
+
+    // The endpoint must not deliver for anyone without the secret
+    let forged = reqwest::Client::new()
+        .post(format!("http://{}/relay", sibling))
+        .bearer_auth("wrong-secret")
+        .json(&json!({ "target_id": bob_id, "message": signal_to(bob_id) }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(forged.status(), reqwest::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn an_unresponsive_sibling_does_not_hold_up_relaying() {
+    let relay_secret = "shared-relay-secret";
+    // Accepts connections through the backlog but never answers, so requests to it time out
+    let hung = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sibling = spawn_node_with(&[("RELAY_SECRET", relay_secret.to_owned())]).await;
+    let node = spawn_node_with(&[
+        ("RELAY_NODES", format!("http://{},http://{}", hung.local_addr().unwrap(), sibling)),
+        ("RELAY_SECRET", relay_secret.to_owned()),
+    ])
+    .await;
+    let (alice_id, bob_id) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut alice = connect(node).await;
+    identify(&mut alice, alice_id).await;
+    let mut bob = connect(sibling).await;
+    identify(&mut bob, bob_id).await;
+
+    let started = std::time::Instant::now();
+    send(&mut alice, signal_to(bob_id)).await;
+    assert_eq!(recv(&mut bob).await["type"], "SIGNAL");
+    assert!(started.elapsed() < Duration::from_secs(1), "relaying waited on the hung sibling: {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn connects_and_disconnects_are_posted_to_the_event_webhook() {
+    let (tx, mut batches) = tokio::sync::mpsc::unbounded_channel();
+    let receiver = axum::Router::new().route(
+        "/events",
+        axum::routing::post(move |axum::Json(batch): axum::Json<Vec<Value>>| async move {
+            tx.send(batch).unwrap();
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+    let node = spawn_node_with(&[("EVENT_WEBHOOK_URL", format!("http://{}/events", hook))]).await;
+    let user_id = Uuid::new_v4();
+    let mut client = connect(node).await;
+    identify(&mut client, user_id).await;
+    client.close(None).await.unwrap();
+
+    let mut events = Vec::new();
+    while events.len() < 2 {
+        let batch = tokio::time::timeout(Duration::from_secs(10), batches.recv()).await.expect("no events posted");
+        events.extend(batch.unwrap());
+    }
+    let summary: Vec<_> = events.iter().map(|event| (event["event"].clone(), event["connection_count"].clone())).collect();
+    assert_eq!(summary, [(json!("connected"), json!(1)), (json!("disconnected"), json!(0))]);
+    assert!(events.iter().all(|event| event["user_id"] == user_id.to_string()));
+}
+
+#[tokio::test]
+async fn signal_by_handle_is_resolved_through_koda_api_and_cached() {
+    let bob_id = Uuid::new_v4();
+    let lookups = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = lookups.clone();
+    let koda_api = axum::Router::new().route(
+        "/internal/users/by-handle/{handle}",
+        axum::routing::get(move |axum::extract::Path(handle): axum::extract::Path<String>| async move {
+            counted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            match handle.as_str() {
+                "bob@koda.test" => Ok(axum::Json(json!({ "id": bob_id }))),
+                _ => Err(axum::http::StatusCode::NOT_FOUND),
+            }
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, koda_api).await.unwrap() });
+
+    let node = spawn_node_with(&[("KODA_API_URL", format!("http://{}", api))]).await;
+    let mut alice = connect(node).await;
+    identify(&mut alice, Uuid::new_v4()).await;
+    let mut bob = connect(node).await;
+    identify(&mut bob, bob_id).await;
+
+    let by_handle = |handle: &str, sdp: &str| {
+        json!({ "type": "SIGNAL_BY_HANDLE", "payload": { "handle": handle, "data": { "sdp": sdp } } }).to_string()
+    };
+    for sdp in ["offer", "renegotiate"] {
+        send(&mut alice, by_handle("bob@koda.test", sdp)).await;
+        let received = recv(&mut bob).await;
+        assert_eq!(received["type"], "SIGNAL");
+        assert_eq!(received["payload"]["data"]["sdp"], sdp);
+    }
+    assert_eq!(lookups.load(std::sync::atomic::Ordering::Relaxed), 1, "the second signal must use the cached id");
+
+    send(&mut alice, by_handle("nobody@koda.test", "offer")).await;
+    let reply = recv(&mut alice).await;
+    assert_eq!(reply["payload"]["code"], "UNKNOWN_HANDLE");
+}
+
+#[tokio::test]
+async fn repeated_bad_tokens_close_the_socket() {
+    let addr = spawn_node().await;
+    let mut client = connect(addr).await;
+    identify(&mut client, Uuid::new_v4()).await;
+    for _ in 0..6 {
+        send(&mut client, json!({ "type": "REIDENTIFY", "payload": { "token": "not-a-jwt" } }).to_string()).await;
+    }
+
+    let mut codes = Vec::new();
+    let close = loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for the close")
+            .expect("socket ended without a close frame")
+            .unwrap();
+        match frame {
+            Message::Text(text) => {
+                let reply: Value = serde_json::from_str(&text).unwrap();
+                codes.push(reply["payload"]["code"].as_str().unwrap().to_owned());
+            }
+            Message::Close(frame) => break frame.expect("close frame must carry a code"),
+            _ => {}
+        }
+    };
+    // The default limit is 5: four ordinary rejections, then the fifth closes the socket
+    assert_eq!(codes, ["INVALID_TOKEN", "INVALID_TOKEN", "INVALID_TOKEN", "INVALID_TOKEN", "TOO_MANY_AUTH_ATTEMPTS"]);
+    assert_eq!(u16::from(close.code), 4001);
+}
+
+#[tokio::test]
+async fn sockets_are_closed_at_their_maximum_lifetime() {
+    let addr = spawn_node_with(&[("MAX_CONNECTION_LIFETIME_SECS", "1".to_owned())]).await;
+    let mut client = connect(addr).await;
+    identify(&mut client, Uuid::new_v4()).await;
+
+    let error = recv(&mut client).await;
+    assert_eq!(error["type"], "ERROR");
+    assert_eq!(error["payload"]["code"], "SESSION_EXPIRED");
+    let close = loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for the close")
+            .expect("socket ended without a close frame")
+            .unwrap();
+        if let Message::Close(frame) = frame {
+            break frame.expect("close frame must carry a code");
+        }
+    };
+    assert_eq!(u16::from(close.code), 4001);
+}
+
+#[tokio::test]
+async fn admin_registry_lists_every_user_sorted_with_device_counts() {
+    let addr = spawn_node_with(&[("ADMIN_TOKEN", "registry-admin-token".to_owned())]).await;
+    let mut users = [Uuid::new_v4(), Uuid::new_v4()];
+    users.sort();
+    let mut sockets = Vec::new();
+    for user_id in [users[1], users[0], users[1]] {
+        let mut client = connect(addr).await;
+        identify(&mut client, user_id).await;
+        sockets.push(client);
+    }
+
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/admin/registry", addr);
+    let registry: Value = client.get(&url).bearer_auth("registry-admin-token").send().await.unwrap().json().await.unwrap();
+    let listed: Vec<_> = registry["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| (entry[0].as_str().unwrap().to_owned(), entry[1].as_u64().unwrap()))
+        .collect();
+    assert_eq!(listed, [(users[0].to_string(), 1), (users[1].to_string(), 2)]);
+    assert!(registry["users"][0][2].as_i64().unwrap() > 0, "last_activity must be a timestamp");
+    assert_eq!(registry["draining"], false);
+
+    let anonymous = client.get(&url).send().await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn http_endpoints_answer_cors_for_allowed_origins_only() {
+    let addr = spawn_node_with(&[("CORS_ALLOWED_ORIGINS", "https://dashboard.koda.test".to_owned())]).await;
+    let client = reqwest::Client::new();
+    let health = |origin: &'static str| client.get(format!("http://{}/health", addr)).header("Origin", origin).send();
+
+    let allowed = health("https://dashboard.koda.test").await.unwrap();
+    assert_eq!(allowed.headers()["access-control-allow-origin"], "https://dashboard.koda.test");
+    let other = health("https://evil.test").await.unwrap();
+    assert!(other.headers().get("access-control-allow-origin").is_none());
+
+    // `/pulse` keeps its own origin check and never gets CORS headers
+    let pulse = client.get(format!("http://{}/pulse", addr)).header("Origin", "https://dashboard.koda.test").send().await.unwrap();
+    assert!(pulse.headers().get("access-control-allow-origin").is_none());
+}